@@ -13,7 +13,7 @@ fn mask_over_gray(c: &mut Criterion, sz: u32) {
         let c = Gray8::from(100);
         m.set_pixel(0, 0, 255);
         m.set_pixel(sz - 1, sz - 1, 128);
-        b.iter(|| raster_over(&mut r, &m, c, 0, 0))
+        b.iter(|| raster_over(&mut r, &m, c, Ch8::MAX, Rounding::Fast, 0, 0))
     });
 }
 
@@ -29,6 +29,56 @@ fn mask_over_gray_512(c: &mut Criterion) {
     mask_over_gray(c, 512);
 }
 
+fn mask_over_gray16(c: &mut Criterion, sz: u32) {
+    let s = format!("mask_over_gray16_{}", sz);
+    c.bench_function(&s, move |b| {
+        let mut r =
+            RasterBuilder::<AssocSGrayAlpha16>::new().with_clear(sz, sz);
+        let mut m = RasterBuilder::<Mask16>::new().with_clear(sz, sz);
+        let c = AssocSGrayAlpha16::new(Ch16::new(25_600));
+        m.set_pixel(0, 0, Mask16::new(Ch16::MAX));
+        m.set_pixel(sz - 1, sz - 1, Mask16::new(Ch16::new(32_768)));
+        b.iter(|| raster_over(&mut r, &m, c, Ch16::MAX, Rounding::Fast, 0, 0))
+    });
+}
+
+fn mask_over_gray16_16(c: &mut Criterion) {
+    mask_over_gray16(c, 16);
+}
+
+fn mask_over_gray16_256(c: &mut Criterion) {
+    mask_over_gray16(c, 256);
+}
+
+fn mask_over_gray16_512(c: &mut Criterion) {
+    mask_over_gray16(c, 512);
+}
+
+fn mask_over_gray32(c: &mut Criterion, sz: u32) {
+    let s = format!("mask_over_gray32_{}", sz);
+    c.bench_function(&s, move |b| {
+        let mut r =
+            RasterBuilder::<AssocSGrayAlpha32>::new().with_clear(sz, sz);
+        let mut m = RasterBuilder::<Mask32>::new().with_clear(sz, sz);
+        let c = AssocSGrayAlpha32::new(0.4);
+        m.set_pixel(0, 0, Mask32::new(1.0));
+        m.set_pixel(sz - 1, sz - 1, Mask32::new(0.5));
+        b.iter(|| raster_over(&mut r, &m, c, Ch32::MAX, Rounding::Fast, 0, 0))
+    });
+}
+
+fn mask_over_gray32_16(c: &mut Criterion) {
+    mask_over_gray32(c, 16);
+}
+
+fn mask_over_gray32_256(c: &mut Criterion) {
+    mask_over_gray32(c, 256);
+}
+
+fn mask_over_gray32_512(c: &mut Criterion) {
+    mask_over_gray32(c, 512);
+}
+
 fn mask_over_rgba(c: &mut Criterion, sz: u32) {
     let s = format!("mask_over_rgba_{}", sz);
     c.bench_function(&s, move |b| {
@@ -37,7 +87,7 @@ fn mask_over_rgba(c: &mut Criterion, sz: u32) {
         let rgba: SepSRgba8 = Rgb::with_alpha(100, 50, 150, 255);
         m.set_pixel(0, 0, 255);
         m.set_pixel(sz - 1, sz - 1, 128);
-        b.iter(|| raster_over(&mut r, &m, rgba, 0, 0))
+        b.iter(|| raster_over(&mut r, &m, rgba, Ch8::MAX, Rounding::Fast, 0, 0))
     });
 }
 
@@ -53,14 +103,82 @@ fn mask_over_rgba_512(c: &mut Criterion) {
     mask_over_rgba(c, 512);
 }
 
+fn mask_over_rgba16(c: &mut Criterion, sz: u32) {
+    let s = format!("mask_over_rgba16_{}", sz);
+    c.bench_function(&s, move |b| {
+        let mut r = RasterBuilder::<AssocSRgba16>::new().with_clear(sz, sz);
+        let mut m = RasterBuilder::<Mask16>::new().with_clear(sz, sz);
+        let rgba = AssocSRgba16::new(
+            Ch16::new(25_600),
+            Ch16::new(12_800),
+            Ch16::new(38_400),
+        );
+        m.set_pixel(0, 0, Mask16::new(Ch16::MAX));
+        m.set_pixel(sz - 1, sz - 1, Mask16::new(Ch16::new(32_768)));
+        b.iter(|| {
+            raster_over(&mut r, &m, rgba, Ch16::MAX, Rounding::Fast, 0, 0)
+        })
+    });
+}
+
+fn mask_over_rgba16_16(c: &mut Criterion) {
+    mask_over_rgba16(c, 16);
+}
+
+fn mask_over_rgba16_256(c: &mut Criterion) {
+    mask_over_rgba16(c, 256);
+}
+
+fn mask_over_rgba16_512(c: &mut Criterion) {
+    mask_over_rgba16(c, 512);
+}
+
+fn mask_over_rgba32(c: &mut Criterion, sz: u32) {
+    let s = format!("mask_over_rgba32_{}", sz);
+    c.bench_function(&s, move |b| {
+        let mut r = RasterBuilder::<AssocSRgba32>::new().with_clear(sz, sz);
+        let mut m = RasterBuilder::<Mask32>::new().with_clear(sz, sz);
+        let rgba = AssocSRgba32::new(0.4, 0.2, 0.6);
+        m.set_pixel(0, 0, Mask32::new(1.0));
+        m.set_pixel(sz - 1, sz - 1, Mask32::new(0.5));
+        b.iter(|| {
+            raster_over(&mut r, &m, rgba, Ch32::MAX, Rounding::Fast, 0, 0)
+        })
+    });
+}
+
+fn mask_over_rgba32_16(c: &mut Criterion) {
+    mask_over_rgba32(c, 16);
+}
+
+fn mask_over_rgba32_256(c: &mut Criterion) {
+    mask_over_rgba32(c, 256);
+}
+
+fn mask_over_rgba32_512(c: &mut Criterion) {
+    mask_over_rgba32(c, 512);
+}
+
 criterion_group!(
     benches,
     mask_over_gray_16,
     mask_over_gray_256,
     mask_over_gray_512,
+    mask_over_gray16_16,
+    mask_over_gray16_256,
+    mask_over_gray16_512,
+    mask_over_gray32_16,
+    mask_over_gray32_256,
+    mask_over_gray32_512,
     mask_over_rgba_16,
     mask_over_rgba_256,
-    mask_over_rgba_512
+    mask_over_rgba_512,
+    mask_over_rgba16_16,
+    mask_over_rgba16_256,
+    mask_over_rgba16_512,
+    mask_over_rgba32_16,
+    mask_over_rgba32_256,
+    mask_over_rgba32_512
 );
 
 criterion_main!(benches);
@@ -3,7 +3,309 @@
 // Copyright (c) 2018-2019  Douglas P Lau
 // Copyright (c) 2020  Jeron Aldaron Lau
 //
-use pix::{Channel, Format};
+use pix::{Ch32, Channel, Format};
+use std::mem::size_of;
+
+/// Rounding policy for 8-bit and 16-bit channel arithmetic.
+///
+/// A policy is passed to each compositing operation.  With `Fast` rounding,
+/// channels are multiplied the same way as `pix`, which can be off by one.
+/// With `Exact` rounding, products are rounded to nearest, and every backend
+/// (scalar and SIMD) gives identical results.  Float channels are not
+/// affected, nor are conversions between formats.  Interpolation (`lerp`)
+/// always rounds to nearest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Approximate rounding
+    Fast,
+    /// Exact rounding to nearest
+    Exact,
+}
+
+/// Porter-Duff compositing operation.
+///
+/// Each operation combines an associated source (`s`) and destination (`d`)
+/// as `s * Fa + d * Fb`, with factors determined by their alpha values.
+///
+/// The operation is passed to `Blend::composite`, `Blend::composite_slice`
+/// or `raster_composite`, instead of having a function for each operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PorterDuff {
+    /// Neither source nor destination (`Fa = 0`, `Fb = 0`)
+    Clear,
+    /// Source only (`Fa = 1`, `Fb = 0`)
+    Src,
+    /// Destination only (`Fa = 0`, `Fb = 1`)
+    Dst,
+    /// Source over destination (`Fa = 1`, `Fb = 1 - As`)
+    SrcOver,
+    /// Destination over source (`Fa = 1 - Ad`, `Fb = 1`)
+    DstOver,
+    /// Source inside destination (`Fa = Ad`, `Fb = 0`)
+    SrcIn,
+    /// Destination inside source (`Fa = 0`, `Fb = As`)
+    DstIn,
+    /// Source outside destination (`Fa = 1 - Ad`, `Fb = 0`)
+    SrcOut,
+    /// Destination outside source (`Fa = 0`, `Fb = 1 - As`)
+    DstOut,
+    /// Source atop destination (`Fa = Ad`, `Fb = 1 - As`)
+    SrcAtop,
+    /// Destination atop source (`Fa = 1 - Ad`, `Fb = As`)
+    DstAtop,
+    /// Source xor destination (`Fa = 1 - Ad`, `Fb = 1 - As`)
+    Xor,
+}
+
+impl PorterDuff {
+    /// Get source and destination factors (`Fa`, `Fb`).
+    ///
+    /// * `src_a` Source alpha value.
+    /// * `dst_a` Destination alpha value.
+    pub(crate) fn factors<H: Channel>(self, src_a: H, dst_a: H) -> (H, H) {
+        use PorterDuff::*;
+        match self {
+            Clear => (H::MIN, H::MIN),
+            Src => (H::MAX, H::MIN),
+            Dst => (H::MIN, H::MAX),
+            SrcOver => (H::MAX, H::MAX - src_a),
+            DstOver => (H::MAX - dst_a, H::MAX),
+            SrcIn => (dst_a, H::MIN),
+            DstIn => (H::MIN, src_a),
+            SrcOut => (H::MAX - dst_a, H::MIN),
+            DstOut => (H::MIN, H::MAX - src_a),
+            SrcAtop => (dst_a, H::MAX - src_a),
+            DstAtop => (H::MAX - dst_a, src_a),
+            Xor => (H::MAX - dst_a, H::MAX - src_a),
+        }
+    }
+}
+
+/// Scale a channel value by a blending factor.
+///
+/// Factors of zero and one are exact, which keeps `Src` and `Dst` from
+/// altering pixels due to rounding.
+pub(crate) fn scale<H: Channel>(v: H, f: H, rnd: Rounding) -> H {
+    if f == H::MAX {
+        v
+    } else if f == H::MIN {
+        H::MIN
+    } else {
+        mul(v, f, rnd)
+    }
+}
+
+/// Multiply two channel values, using a rounding policy.
+pub(crate) fn mul<H: Channel>(a: H, b: H, rnd: Rounding) -> H {
+    if rnd == Rounding::Fast {
+        return a * b;
+    }
+    match size_of::<H>() {
+        1 => bits(mul_exact_u8(bits(a), bits(b))),
+        2 => bits(mul_exact_u16(bits(a), bits(b))),
+        _ => a * b,
+    }
+}
+
+/// Multiply u8 values as channels, rounding to nearest.
+pub(crate) fn mul_exact_u8(a: u8, b: u8) -> u8 {
+    let c = u32::from(a) * u32::from(b) + 0x80;
+    ((c + (c >> 8)) >> 8) as u8
+}
+
+/// Multiply u16 values as channels, rounding to nearest.
+pub(crate) fn mul_exact_u16(a: u16, b: u16) -> u16 {
+    let c = u64::from(a) * u64::from(b) + 0x8000;
+    ((c + (c >> 16)) >> 16) as u16
+}
+
+/// Separate a mask color channel from alpha, for SIMD kernels.
+///
+/// Mask pixels are converted to white, so separating from alpha and
+/// multiplying by white matches the scalar fallback.
+#[cfg(all(
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "aarch64"
+    ),
+    feature = "simd"
+))]
+pub(crate) fn separate_mask<H: Channel>(c: H, a: H, rnd: Rounding) -> H {
+    mul(c / a, H::MAX, rnd)
+}
+
+/// Reinterpret a value as another type of the same size.
+///
+/// The caller must check that `T` is the same size as `U`.
+pub(crate) fn bits<T: Copy, U: Copy>(t: T) -> U {
+    debug_assert_eq!(size_of::<T>(), size_of::<U>());
+    unsafe { std::mem::transmute_copy(&t) }
+}
+
+/// Blend mode for combining source and destination colors.
+///
+/// These are the modes from the W3C *Compositing and Blending Level 1*
+/// specification.  Blending is followed by a source-over composite.
+///
+/// The non-separable modes (`Hue`, `Saturation`, `Color` and `Luminosity`)
+/// operate on whole RGB triplets.  Gray formats have no hue or saturation,
+/// so `Hue`, `Saturation` and `Color` keep the destination value, while
+/// `Luminosity` uses the source value.  Mask formats have no color, so all
+/// modes are equivalent to `over`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Source color only
+    Normal,
+    /// Product of source and destination
+    Multiply,
+    /// Inverse product of inverted source and destination
+    Screen,
+    /// Multiply or screen, depending on destination
+    Overlay,
+    /// Darker of source and destination
+    Darken,
+    /// Lighter of source and destination
+    Lighten,
+    /// Brighten destination to reflect source
+    ColorDodge,
+    /// Darken destination to reflect source
+    ColorBurn,
+    /// Multiply or screen, depending on source
+    HardLight,
+    /// Darken or lighten, depending on source
+    SoftLight,
+    /// Absolute difference of source and destination
+    Difference,
+    /// Like difference, but with lower contrast
+    Exclusion,
+    /// Hue of source, with saturation and luminosity of destination
+    Hue,
+    /// Saturation of source, with hue and luminosity of destination
+    Saturation,
+    /// Hue and saturation of source, with luminosity of destination
+    Color,
+    /// Luminosity of source, with hue and saturation of destination
+    Luminosity,
+}
+
+impl BlendMode {
+    /// Check if the blend mode is separable (blends each channel
+    /// independently).
+    pub fn is_separable(self) -> bool {
+        use BlendMode::*;
+        !matches!(self, Hue | Saturation | Color | Luminosity)
+    }
+
+    /// Blend one separated channel (`B(Cb, Cs)`).
+    ///
+    /// Non-separable modes are treated as though both channels are gray.
+    ///
+    /// * `cb` Backdrop (destination) value.
+    /// * `cs` Source value.
+    fn blend_separated(self, cb: f32, cs: f32) -> f32 {
+        use BlendMode::*;
+        match self {
+            Normal => cs,
+            Multiply => cb * cs,
+            Screen => screen(cb, cs),
+            Overlay => hard_light(cs, cb),
+            Darken => cb.min(cs),
+            Lighten => cb.max(cs),
+            ColorDodge => {
+                if cb <= 0.0 {
+                    0.0
+                } else if cs >= 1.0 {
+                    1.0
+                } else {
+                    (cb / (1.0 - cs)).min(1.0)
+                }
+            }
+            ColorBurn => {
+                if cb >= 1.0 {
+                    1.0
+                } else if cs <= 0.0 {
+                    0.0
+                } else {
+                    1.0 - ((1.0 - cb) / cs).min(1.0)
+                }
+            }
+            HardLight => hard_light(cb, cs),
+            SoftLight => soft_light(cb, cs),
+            Difference => (cb - cs).abs(),
+            Exclusion => cb + cs - 2.0 * cb * cs,
+            Hue | Saturation | Color => cb,
+            Luminosity => cs,
+        }
+    }
+
+    /// Blend one associated channel, then composite with source-over.
+    ///
+    /// * `sc` Source channel (associated).
+    /// * `sa` Source alpha.
+    /// * `dc` Destination channel (associated).
+    /// * `da` Destination alpha.
+    pub(crate) fn blend_channel(
+        self,
+        sc: f32,
+        sa: f32,
+        dc: f32,
+        da: f32,
+    ) -> f32 {
+        let cs = separate(sc, sa);
+        let cb = separate(dc, da);
+        let b = self.blend_separated(cb, cs);
+        blend_over(b, sc, sa, dc, da)
+    }
+}
+
+/// Composite a blended channel with source-over.
+///
+/// * `b` Blended (separated) channel value.
+/// * `sc` Source channel (associated).
+/// * `sa` Source alpha.
+/// * `dc` Destination channel (associated).
+/// * `da` Destination alpha.
+pub(crate) fn blend_over(b: f32, sc: f32, sa: f32, dc: f32, da: f32) -> f32 {
+    sc * (1.0 - da) + dc * (1.0 - sa) + sa * da * b
+}
+
+/// Separate an associated channel from alpha.
+pub(crate) fn separate(c: f32, a: f32) -> f32 {
+    if a > 0.0 {
+        (c / a).min(1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Screen blend function
+fn screen(cb: f32, cs: f32) -> f32 {
+    cb + cs - cb * cs
+}
+
+/// Hard light blend function
+fn hard_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb * 2.0 * cs
+    } else {
+        screen(cb, 2.0 * cs - 1.0)
+    }
+}
+
+/// Soft light blend function
+fn soft_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+    } else {
+        let d = if cb <= 0.25 {
+            ((16.0 * cb - 12.0) * cb + 4.0) * cb
+        } else {
+            cb.sqrt()
+        };
+        cb + (2.0 * cs - 1.0) * (d - cb)
+    }
+}
 
 /// Pixel format which can be blended.
 pub trait Blend: Format {
@@ -12,14 +314,21 @@ pub trait Blend: Format {
     /// * `dst` Destination pixels.
     /// * `src` Source pixels.
     /// * `clr` Mask color.
-    fn over_slice<B, H>(dst: &mut [Self], src: &[B], clr: Self)
-    where
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_slice<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
         B: Format<Chan = H>,
         Self::Chan: From<H>,
         H: Channel,
         H: From<Self::Chan>,
     {
-        Self::over_fallback(dst, src, clr);
+        Self::over_fallback(dst, src, clr, opacity, rnd);
     }
 
     /// Blend pixels with `over` operation (slow fallback).
@@ -27,18 +336,283 @@ pub trait Blend: Format {
     /// * `dst` Destination pixels.
     /// * `src` Source pixels.
     /// * `clr` Mask color.
-    fn over_fallback<B, H>(dst: &mut [Self], src: &[B], clr: Self)
-    where
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
         B: Format<Chan = H>,
         Self::Chan: From<H>,
         H: Channel,
         H: From<Self::Chan>;
 
     /// Blend pixel on top of another, using "over".
-    fn over<B, H>(dst: Self, src: B) -> Self
+    fn over<B, H>(dst: Self, src: B, rnd: Rounding) -> Self
     where
         B: Format<Chan = H>,
         Self::Chan: From<H>,
         H: Channel,
         H: From<Self::Chan>;
+
+    /// Blend pixels with `over` operation, in linear light.
+    ///
+    /// sRGB pixels are decoded to linear intensity, blended, and encoded
+    /// again, so antialiased edges are not too dark.  Other pixels are
+    /// blended with `over_slice`.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_linear_slice<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        Self::Chan: From<H> + From<Ch32>,
+        H: Channel,
+        H: From<Self::Chan>,
+        Ch32: From<Self::Chan> + From<H>,
+    {
+        crate::linear::over_slice(dst, src, clr, opacity, rnd);
+    }
+
+    /// Blend pixels with `over` operation, clipped by a mask.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `mask` Mask pixels (alpha is coverage of source).
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_masked_slice<B, M, H>(
+        dst: &mut [Self],
+        src: &[B],
+        mask: &[M],
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        M: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>,
+    {
+        Self::over_masked_fallback(dst, src, mask, opacity, rnd);
+    }
+
+    /// Blend pixels with `over` operation, clipped by a mask (slow
+    /// fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `mask` Mask pixels (alpha is coverage of source).
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_masked_fallback<B, M, H>(
+        dst: &mut [Self],
+        src: &[B],
+        mask: &[M],
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        M: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>;
+
+    /// Composite pixels with a Porter-Duff operation.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `op` Compositing operation.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn composite_slice<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        op: PorterDuff,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>,
+    {
+        Self::composite_fallback(dst, src, clr, op, opacity, rnd);
+    }
+
+    /// Composite pixels with a Porter-Duff operation (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `op` Compositing operation.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn composite_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        op: PorterDuff,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>;
+
+    /// Composite one pixel with another, using a Porter-Duff operation.
+    fn composite<B, H>(
+        dst: Self,
+        src: B,
+        op: PorterDuff,
+        rnd: Rounding,
+    ) -> Self
+    where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>;
+
+    /// Blend pixels with a blend mode.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `mode` Blend mode.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn blend_slice<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        mode: BlendMode,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>,
+        H: From<Ch32>,
+        Ch32: From<H>,
+    {
+        Self::blend_fallback(dst, src, clr, mode, opacity, rnd);
+    }
+
+    /// Blend pixels with a blend mode (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `mode` Blend mode.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn blend_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        mode: BlendMode,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>,
+        H: From<Ch32>,
+        Ch32: From<H>;
+
+    /// Blend pixel on top of another, using a blend mode.
+    fn blend<B, H>(dst: Self, src: B, mode: BlendMode) -> Self
+    where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<Self::Chan>,
+        H: From<Ch32>,
+        Ch32: From<H>;
+
+    /// Interpolate pixels linearly, for cross-fades.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `t` Interpolation from destination (`MIN`) to source (`MAX`).
+    fn lerp_slice(dst: &mut [Self], src: &[Self], t: Self::Chan) {
+        for (d, s) in dst.iter_mut().zip(src) {
+            *d = Self::lerp(*d, *s, t);
+        }
+    }
+
+    /// Interpolate one pixel linearly with another.
+    ///
+    /// Translucent pixels are interpolated with associated alpha, for both
+    /// alpha modes.  Channels are interpolated without converting gamma.
+    ///
+    /// * `a` First pixel.
+    /// * `b` Second pixel.
+    /// * `t` Interpolation from `a` (`MIN`) to `b` (`MAX`).
+    fn lerp(a: Self, b: Self, t: Self::Chan) -> Self;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixture::{gray16, gray8, mask16, mask8, rgba16, rgba8};
+    use pix::*;
+
+    /// Check that `over_slice` matches `over_fallback` with exact rounding.
+    fn assert_same<F, B>(dst: Vec<F>, src: &[B], clr: F, opacity: F::Chan)
+    where
+        F: Blend + std::fmt::Debug,
+        B: Format<Chan = F::Chan>,
+    {
+        let mut pix = dst.clone();
+        let mut fallback = dst;
+        F::over_slice(&mut pix, src, clr, opacity, Rounding::Exact);
+        F::over_fallback(&mut fallback, src, clr, opacity, Rounding::Exact);
+        assert_eq!(pix, fallback);
+    }
+
+    #[test]
+    fn backends_identical() {
+        for len in 0..200 {
+            let o8 = Ch8::new(0xFF - len as u8);
+            let o16 = Ch16::new(0xFFFF - len as u16 * 300);
+            let (m8, m16) = (mask8(len), mask16(len));
+            let clr = AssocSRgba8::with_alpha(0x20, 0x40, 0x60, 0x80);
+            assert_same(rgba8(len), &m8, clr, o8);
+            let clr = AssocSGrayAlpha8::with_alpha(0x40, 0xC0);
+            assert_same(gray8(len), &m8, clr, o8);
+            let clr = AssocSRgba16::with_alpha(0x2000, 0x4000, 0x6000, 0x8000);
+            assert_same(rgba16(len), &m16, clr, o16);
+            let clr = AssocSGrayAlpha16::with_alpha(0x4000, 0xC000);
+            assert_same(gray16(len), &m16, clr, o16);
+        }
+    }
+
+    #[test]
+    fn copy_identity() {
+        let src: Vec<_> = (0..=0xFF_u8)
+            .flat_map(|a| (0..=a).map(move |c| (c, a)))
+            .map(|(c, a)| AssocSRgba8::with_alpha(c, c, c, a))
+            .collect();
+        let mut dst = vec![AssocSRgba8::default(); src.len()];
+        let clr = AssocSRgba8::new(0xFF, 0xFF, 0xFF);
+        AssocSRgba8::over_slice(&mut dst, &src, clr, Ch8::MAX, Rounding::Exact);
+        assert_eq!(dst, src);
+    }
 }
@@ -0,0 +1,226 @@
+// blur.rs      Raster blur operations.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use crate::resize::{from_format, to_format, Pixel};
+use pix::{Ch32, Format, Raster};
+
+/// Blur a raster with a Gaussian kernel.
+///
+/// Blurring is done with associated alpha and linear gamma, so transparent
+/// pixels do not darken edges.  Pixels outside of the raster are
+/// transparent, so the kernel is limited to the size of the raster.
+///
+/// * `raster` Raster to blur in place.
+/// * `sigma` Standard deviation of Gaussian, in pixels.  The raster is
+///   unchanged unless it is positive and finite.
+pub fn blur_gaussian<F>(raster: &mut Raster<F>, sigma: f32)
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    if !sigma.is_finite() || sigma <= 0.0 {
+        return;
+    }
+    let max = raster.width().max(raster.height()) as f32;
+    let radius = (sigma * 3.0).ceil().min(max) as usize;
+    let mut kernel: Vec<f32> = (0..=radius * 2)
+        .map(|i| {
+            let x = i as f32 - radius as f32;
+            (-x * x / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|k| *k /= sum);
+    blur_passes(raster, |src, dst| gaussian_line(src, dst, &kernel));
+}
+
+/// Blur a raster with box filters, approximating a Gaussian blur.
+///
+/// Three box passes are done on each axis, which is much faster than
+/// [blur_gaussian](fn.blur_gaussian.html) for large `sigma`.  Boxes are
+/// limited to the size of the raster.
+///
+/// * `raster` Raster to blur in place.
+/// * `sigma` Standard deviation of Gaussian, in pixels.  The raster is
+///   unchanged unless it is positive and finite.
+pub fn blur_box<F>(raster: &mut Raster<F>, sigma: f32)
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    if !sigma.is_finite() || sigma <= 0.0 {
+        return;
+    }
+    let max = raster.width().max(raster.height()) as f32;
+    let radii = box_radii(sigma.min(max));
+    let mut tmp = Vec::new();
+    blur_passes(raster, |src, dst| {
+        tmp.clear();
+        tmp.extend_from_slice(src);
+        for radius in radii.iter() {
+            box_line(&tmp, dst, *radius);
+            tmp.copy_from_slice(dst);
+        }
+    });
+}
+
+/// Get radii of three box filters which approximate a Gaussian.
+fn box_radii(sigma: f32) -> [usize; 3] {
+    let n = 3.0;
+    let ideal = (12.0 * sigma * sigma / n + 1.0).sqrt();
+    let mut wl = ideal.floor() as i32;
+    if wl % 2 == 0 {
+        wl -= 1;
+    }
+    let wu = wl + 2;
+    let w = wl as f32;
+    let m = ((12.0 * sigma * sigma - n * w * w - 4.0 * n * w - 3.0 * n)
+        / (-4.0 * w - 4.0))
+        .round() as i32;
+    let radius = |i: i32| (if i < m { wl } else { wu } as usize - 1) / 2;
+    [radius(0), radius(1), radius(2)]
+}
+
+/// Blur each row and column of a raster.
+///
+/// * `raster` Raster to blur in place.
+/// * `line` Function to blur one line of pixels.
+fn blur_passes<F, L>(raster: &mut Raster<F>, mut line: L)
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+    L: FnMut(&[Pixel], &mut [Pixel]),
+{
+    let w = raster.width() as usize;
+    let h = raster.height() as usize;
+    if w == 0 || h == 0 {
+        return;
+    }
+    let pixels: Vec<Pixel> =
+        raster.as_slice().iter().map(|p| from_format(*p)).collect();
+    let mut tmp = vec![[0.0; 4]; w * h];
+    for (src, dst) in pixels.chunks(w).zip(tmp.chunks_mut(w)) {
+        line(src, dst);
+    }
+    let cols = transpose(&tmp, w, h);
+    for (src, dst) in cols.chunks(h).zip(tmp.chunks_mut(h)) {
+        line(src, dst);
+    }
+    let pixels = transpose(&tmp, h, w);
+    for (d, p) in raster.as_slice_mut().iter_mut().zip(pixels) {
+        *d = to_format(p);
+    }
+}
+
+/// Transpose a buffer of pixels.
+fn transpose(pixels: &[Pixel], w: usize, h: usize) -> Vec<Pixel> {
+    let mut t = Vec::with_capacity(w * h);
+    for x in 0..w {
+        t.extend((0..h).map(|y| pixels[y * w + x]));
+    }
+    t
+}
+
+/// Blur a line of pixels with a Gaussian kernel.
+fn gaussian_line(src: &[Pixel], dst: &mut [Pixel], kernel: &[f32]) {
+    let radius = kernel.len() / 2;
+    for (i, d) in dst.iter_mut().enumerate() {
+        let mut p = [0.0; 4];
+        let j0 = i.saturating_sub(radius);
+        let j1 = (i + radius + 1).min(src.len());
+        for j in j0..j1 {
+            let k = kernel[j + radius - i];
+            for c in 0..4 {
+                p[c] += src[j][c] * k;
+            }
+        }
+        *d = p;
+    }
+}
+
+/// Blur a line of pixels with a box filter, using a running sum.
+fn box_line(src: &[Pixel], dst: &mut [Pixel], radius: usize) {
+    let scale = 1.0 / (radius * 2 + 1) as f32;
+    let mut sum = [0.0; 4];
+    for s in src.iter().take(radius) {
+        add_pixel(&mut sum, s, 1.0);
+    }
+    for (i, d) in dst.iter_mut().enumerate() {
+        if let Some(s) = src.get(i + radius) {
+            add_pixel(&mut sum, s, 1.0);
+        }
+        if i > radius {
+            add_pixel(&mut sum, &src[i - radius - 1], -1.0);
+        }
+        for c in 0..4 {
+            d[c] = sum[c] * scale;
+        }
+    }
+}
+
+/// Add a weighted pixel to a sum
+fn add_pixel(sum: &mut Pixel, p: &Pixel, w: f32) {
+    for c in 0..4 {
+        sum[c] += p[c] * w;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pix::*;
+
+    #[test]
+    fn transparent_edges() {
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(5, 5);
+        r.set_pixel(2, 2, SepSRgba8::new(0xFF, 0xFF, 0xFF));
+        let mut b = RasterBuilder::<SepSRgba8>::new().with_clear(5, 5);
+        b.set_pixel(2, 2, SepSRgba8::new(0xFF, 0xFF, 0xFF));
+        blur_gaussian(&mut r, 1.0);
+        blur_box(&mut b, 1.0);
+        for p in r.as_slice().iter().chain(b.as_slice()) {
+            let opaque = p.red() == Ch8::new(0xFF);
+            assert_eq!(p.alpha().value() > Ch8::new(0), opaque);
+        }
+        assert_eq!(r.pixel(1, 2), r.pixel(2, 3));
+        assert_eq!(b.pixel(3, 2), b.pixel(2, 1));
+    }
+
+    #[test]
+    fn box_radii() {
+        assert_eq!(super::box_radii(1.0), [0, 0, 1]);
+        assert_eq!(super::box_radii(4.0), [3, 3, 4]);
+    }
+
+    #[test]
+    fn uniform() {
+        let mut r = RasterBuilder::<SepSRgba8>::new()
+            .with_color(9, 9, SepSRgba8::new(0x40, 0x80, 0xC0));
+        blur_box(&mut r, 1.0);
+        assert_eq!(r.pixel(4, 4), SepSRgba8::new(0x40, 0x80, 0xC0));
+        assert!(r.pixel(0, 0).alpha().value() < Ch8::new(0xFF));
+    }
+
+    #[test]
+    fn large_sigma() {
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(3, 3);
+        r.set_pixel(1, 1, SepSRgba8::new(0xFF, 0xFF, 0xFF));
+        let v = r.as_u8_slice().to_vec();
+        blur_gaussian(&mut r, f32::NAN);
+        blur_gaussian(&mut r, f32::INFINITY);
+        blur_box(&mut r, f32::INFINITY);
+        assert_eq!(r.as_u8_slice(), &v[..]);
+        let mut b = RasterBuilder::<SepSRgba8>::new().with_clear(3, 3);
+        b.set_pixel(1, 1, SepSRgba8::new(0xFF, 0xFF, 0xFF));
+        blur_gaussian(&mut r, 1.0e30);
+        blur_box(&mut b, 1.0e30);
+        let a = r.pixel(0, 0).alpha().value();
+        assert!(a > Ch8::new(0));
+        assert!(r.as_slice().iter().all(|p| p.alpha().value() == a));
+        assert!(b.pixel(1, 1).alpha().value() > Ch8::new(0));
+    }
+}
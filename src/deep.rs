@@ -0,0 +1,516 @@
+// deep.rs      SIMD compositing for 16-bit and float pixels.
+//
+// Copyright (c) 2018-2019  Douglas P Lau
+//
+use crate::blend::{bits, scale, separate_mask, Rounding};
+use pix::{AlphaMode, AlphaModeID, Format, GammaMode, GammaModeID};
+use std::mem::size_of;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Number of pixels to convert mask values for at a time.
+const BLOCK: usize = 64;
+
+/// Check if formats are associated 16-bit or float pixels over a mask with
+/// the same channel type.
+///
+/// With 2-byte channels, `F::Chan` can only be `Ch16`, and with 4-byte
+/// channels, only `Ch32`.  The only format with unknown alpha and gamma modes
+/// is `Mask`.
+fn is_deep_over_mask<F: Format, B: Format>(channels: usize) -> bool {
+    let chan = size_of::<F::Chan>();
+    <F as AlphaMode>::ID == AlphaModeID::Associated
+        && (chan == 2 || chan == 4)
+        && size_of::<F>() == chan * channels
+        && <B as AlphaMode>::ID == AlphaModeID::UnknownAlpha
+        && <B as GammaMode>::ID == GammaModeID::UnknownGamma
+        && size_of::<B>() == chan
+        && size_of::<B::Chan>() == chan
+}
+
+/// Get the mask color constants for the RGBA kernels.
+///
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// With exact rounding of 16-bit channels, returns the associated color
+/// scaled by opacity, which is multiplied directly with the mask.
+/// Otherwise, returns color channels separated from alpha, followed by alpha
+/// scaled by opacity.
+fn mask_color<F: Format>(
+    clr: F,
+    opacity: F::Chan,
+    rnd: Rounding,
+) -> [F::Chan; 4] {
+    let rgba = clr.rgba();
+    if rnd == Rounding::Exact && size_of::<F::Chan>() == 2 {
+        return rgba.map(|c| scale(c, opacity, rnd));
+    }
+    let a = rgba[3];
+    let sep = |c| separate_mask(c, a, rnd);
+    [sep(rgba[0]), sep(rgba[1]), sep(rgba[2]), scale(a, opacity, rnd)]
+}
+
+/// Get the gray value of a mask pixel, converted to a gray format.
+///
+/// Converting a mask to a gray format encodes gamma, so this depends on the
+/// gamma mode of `F`.
+fn mask_gray<F, B>(mask: B) -> F::Chan
+where
+    F: Format,
+    B: Format,
+    F::Chan: From<B::Chan>,
+{
+    let gray: F = mask.convert();
+    gray.rgba()[0]
+}
+
+/// Get the gray values of mask pixels, converted to a gray format.
+///
+/// Masks usually have runs of the same value, so the last conversion is
+/// reused to avoid decoding gamma for every pixel.
+fn mask_grays<F, B, T>(src: &[B], gray: &mut [T])
+where
+    F: Format,
+    B: Format,
+    F::Chan: From<B::Chan>,
+    T: Copy,
+{
+    let mut last = None;
+    for (g, m) in gray.iter_mut().zip(src) {
+        let v = match last {
+            Some((l, v)) if l == *m => v,
+            _ => bits(mask_gray::<F, B>(*m)),
+        };
+        last = Some((*m, v));
+        *g = v;
+    }
+}
+
+/// Blend associated 16-bit or float RGBA pixels with a mask, using SIMD.
+///
+/// Other formats are ignored.  Any pixels remaining at the end of the slices
+/// must be blended by the caller.
+///
+/// * `dst` Destination pixels (must be an `Rgb` format).
+/// * `src` Source mask pixels.
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// Returns the number of pixels blended.
+pub(crate) fn over_rgba_slice<F, B>(
+    dst: &mut [F],
+    src: &[B],
+    clr: F,
+    opacity: B::Chan,
+    rnd: Rounding,
+) -> usize
+where
+    F: Format,
+    B: Format,
+{
+    if !is_deep_over_mask::<F, B>(4) || !is_x86_feature_detected!("sse4.1") {
+        return 0;
+    }
+    let len = dst.len().min(src.len());
+    let clr = mask_color(clr, bits(opacity), rnd);
+    let pix = dst.as_mut_ptr();
+    let mask = src.as_ptr();
+    if size_of::<F::Chan>() == 2 {
+        let clr = [bits(clr[0]), bits(clr[1]), bits(clr[2]), bits(clr[3])];
+        let exact = rnd == Rounding::Exact;
+        unsafe { over_rgba16_sse41(pix as _, mask as _, len, clr, exact) }
+    } else {
+        let clr = [bits(clr[0]), bits(clr[1]), bits(clr[2]), bits(clr[3])];
+        unsafe { over_rgba32_sse41(pix as _, mask as _, len, clr) }
+    }
+}
+
+/// Blend associated 16-bit or float Gray+alpha pixels with a mask, using
+/// SIMD.
+///
+/// Other formats are ignored.  Any pixels remaining at the end of the slices
+/// must be blended by the caller.
+///
+/// * `dst` Destination pixels (must be a `Gray` format).
+/// * `src` Source mask pixels.
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// Returns the number of pixels blended.
+pub(crate) fn over_gray_slice<F, B>(
+    dst: &mut [F],
+    src: &[B],
+    clr: F,
+    opacity: B::Chan,
+    rnd: Rounding,
+) -> usize
+where
+    F: Format,
+    B: Format,
+    F::Chan: From<B::Chan>,
+{
+    if !is_deep_over_mask::<F, B>(2) || !is_x86_feature_detected!("sse4.1") {
+        return 0;
+    }
+    let len = dst.len().min(src.len());
+    let opacity = F::Chan::from(opacity);
+    let rgba = clr.rgba().map(|c| scale(c, opacity, rnd));
+    let exact = rnd == Rounding::Exact;
+    let mut n = 0;
+    for (dst, src) in dst[..len].chunks_mut(BLOCK).zip(src.chunks(BLOCK)) {
+        let pix = dst.as_mut_ptr();
+        let mask = src.as_ptr();
+        let b = if size_of::<F::Chan>() == 2 {
+            let mut gray = [0; BLOCK];
+            mask_grays::<F, B, _>(src, &mut gray);
+            let clr = [bits(rgba[0]), bits(rgba[3])];
+            let len = dst.len();
+            unsafe {
+                over_gray16_sse41(pix as _, mask as _, &gray, len, clr, exact)
+            }
+        } else {
+            let mut gray = [0.0; BLOCK];
+            mask_grays::<F, B, _>(src, &mut gray);
+            let clr = [bits(rgba[0]), bits(rgba[3])];
+            unsafe {
+                over_gray32_sse41(pix as _, mask as _, &gray, dst.len(), clr)
+            }
+        };
+        n += b;
+        if b < dst.len() {
+            break;
+        }
+    }
+    n
+}
+
+/// Composite a color with a mask onto 16-bit RGBA pixels (SSE4.1).
+///
+/// * `clr` Mask color, separated from alpha, followed by alpha.  With
+///   `exact`, this is the associated color instead.
+/// * `exact` Round products to nearest.
+///
+/// Returns the number of pixels blended (a multiple of 2).
+#[target_feature(enable = "sse4.1")]
+unsafe fn over_rgba16_sse41(
+    pix: *mut u16,
+    mask: *const u16,
+    len: usize,
+    clr: [u16; 4],
+    exact: bool,
+) -> usize {
+    let [r, g, b, a] = clr;
+    let c = _mm_set_epi16(
+        a as i16, b as i16, g as i16, r as i16, a as i16, b as i16, g as i16,
+        r as i16,
+    );
+    let ca = _mm_set1_epi16(a as i16);
+    // Truncate len to multiple of 2
+    let len2 = (len >> 1) << 1;
+    // 2 pixels at a time
+    for i in (0..len2).step_by(2) {
+        let dst = pix.add(i * 4) as *mut __m128i;
+        let src = mask.add(i) as *const i32;
+        // get 2 mask values from src,
+        // then shuffle: xxxxxx10 => 11110000
+        let m = _mm_set1_epi32(std::ptr::read_unaligned(src));
+        let m = _mm_shuffle_epi8(
+            m,
+            _mm_set_epi8(3, 2, 3, 2, 3, 2, 3, 2, 1, 0, 1, 0, 1, 0, 1, 0),
+        );
+        // Source alpha: mask alpha * mask value
+        let sa = mul_u16x8_sse41(ca, m, exact);
+        // Source: separated color * source alpha, mask alpha * mask value,
+        // or associated color * mask value when exact
+        let m = if exact {
+            m
+        } else {
+            _mm_blend_epi16(sa, m, 0b1000_1000)
+        };
+        let s = mul_u16x8_sse41(c, m, exact);
+        // Over: s + b * (1 - sa)
+        let bot = _mm_loadu_si128(dst);
+        let one_minus_sa = _mm_xor_si128(sa, _mm_set1_epi8(-1));
+        let b = mul_u16x8_sse41(bot, one_minus_sa, exact);
+        let out = _mm_adds_epu16(s, b);
+        _mm_storeu_si128(dst, out);
+    }
+    len2
+}
+
+/// Composite a color with a mask onto 16-bit Gray+alpha pixels (SSE4.1).
+///
+/// * `gray` Mask values converted to gray values.
+/// * `clr` Mask color value (associated) and alpha.
+/// * `exact` Round products to nearest.
+///
+/// Returns the number of pixels blended (a multiple of 4).
+#[target_feature(enable = "sse4.1")]
+unsafe fn over_gray16_sse41(
+    pix: *mut u16,
+    mask: *const u16,
+    gray: &[u16],
+    len: usize,
+    clr: [u16; 2],
+    exact: bool,
+) -> usize {
+    let [v, a] = clr;
+    let c = _mm_set_epi16(
+        a as i16, v as i16, a as i16, v as i16, a as i16, v as i16, a as i16,
+        v as i16,
+    );
+    // Truncate len to multiple of 4
+    let len4 = (len >> 2) << 2;
+    // 4 pixels at a time
+    for i in (0..len4).step_by(4) {
+        let dst = pix.add(i * 2) as *mut __m128i;
+        let src = mask.add(i) as *const i64;
+        let g = gray.as_ptr().add(i) as *const i64;
+        // interleave gray and mask values: 3210, 3210 => 33221100
+        let m = _mm_unpacklo_epi16(
+            _mm_loadl_epi64(g as *const __m128i),
+            _mm_loadl_epi64(src as *const __m128i),
+        );
+        // Source: mask color * mask gray, mask alpha * mask value
+        let s = mul_u16x8_sse41(c, m, exact);
+        // Source alpha, for each channel
+        let sa = _mm_shuffle_epi8(
+            s,
+            _mm_set_epi8(
+                15, 14, 15, 14, 11, 10, 11, 10, 7, 6, 7, 6, 3, 2, 3, 2,
+            ),
+        );
+        // Over: s + b * (1 - sa)
+        let bot = _mm_loadu_si128(dst);
+        let one_minus_sa = _mm_xor_si128(sa, _mm_set1_epi8(-1));
+        let b = mul_u16x8_sse41(bot, one_minus_sa, exact);
+        let out = _mm_adds_epu16(s, b);
+        _mm_storeu_si128(dst, out);
+    }
+    len4
+}
+
+/// Multiply u16 values as `Ch16` channels (SSE4.1).
+#[target_feature(enable = "sse4.1")]
+unsafe fn mul_u16x8_sse41(a: __m128i, b: __m128i, exact: bool) -> __m128i {
+    let al = _mm_cvtepu16_epi32(a);
+    let bl = _mm_cvtepu16_epi32(b);
+    let ah = _mm_cvtepu16_epi32(_mm_srli_si128(a, 8));
+    let bh = _mm_cvtepu16_epi32(_mm_srli_si128(b, 8));
+    let (lo, hi) = if exact {
+        (mul_exact_u32x4_sse41(al, bl), mul_exact_u32x4_sse41(ah, bh))
+    } else {
+        (mul_u32x4_sse41(al, bl), mul_u32x4_sse41(ah, bh))
+    };
+    _mm_packus_epi32(lo, hi)
+}
+
+/// Multiply u16 values in u32 lanes as `Ch16` channels (SSE4.1).
+#[target_feature(enable = "sse4.1")]
+unsafe fn mul_u32x4_sse41(a: __m128i, b: __m128i) -> __m128i {
+    // Same as Ch16 multiply: expand each value to 24 bits (v << 8 | v >> 8),
+    // then keep the high 16 bits of the 48-bit product.
+    let a = _mm_or_si128(_mm_slli_epi32(a, 8), _mm_srli_epi32(a, 8));
+    let b = _mm_or_si128(_mm_slli_epi32(b, 8), _mm_srli_epi32(b, 8));
+    let even = _mm_mul_epu32(a, b);
+    let odd = _mm_mul_epu32(_mm_srli_epi64(a, 32), _mm_srli_epi64(b, 32));
+    let hi = _mm_set_epi32(-1, 0, -1, 0);
+    _mm_or_si128(_mm_srli_epi64(even, 32), _mm_and_si128(odd, hi))
+}
+
+/// Multiply u16 values in u32 lanes, rounding to nearest (SSE4.1).
+#[target_feature(enable = "sse4.1")]
+unsafe fn mul_exact_u32x4_sse41(a: __m128i, b: __m128i) -> __m128i {
+    // c = a * b + 32768, then (c + (c >> 16)) >> 16
+    let c = _mm_add_epi32(_mm_mullo_epi32(a, b), _mm_set1_epi32(0x8000));
+    _mm_srli_epi32(_mm_add_epi32(c, _mm_srli_epi32(c, 16)), 16)
+}
+
+/// Composite a color with a mask onto float RGBA pixels (SSE4.1).
+///
+/// * `clr` Mask color, separated from alpha, followed by alpha.
+///
+/// Returns the number of pixels blended.
+#[target_feature(enable = "sse4.1")]
+unsafe fn over_rgba32_sse41(
+    pix: *mut f32,
+    mask: *const f32,
+    len: usize,
+    clr: [f32; 4],
+) -> usize {
+    let c = _mm_loadu_ps(clr.as_ptr());
+    let ca = _mm_set1_ps(clr[3]);
+    let one = _mm_set1_ps(1.0);
+    for i in 0..len {
+        let dst = pix.add(i * 4);
+        let m = _mm_set1_ps(*mask.add(i));
+        // Source alpha: mask alpha * mask value
+        let sa = _mm_mul_ps(ca, m);
+        // Source: separated color * source alpha, mask alpha * mask value
+        let s = _mm_mul_ps(c, _mm_blend_ps(sa, m, 0b1000));
+        // Over: s + b * (1 - sa)
+        let bot = _mm_loadu_ps(dst);
+        let out = _mm_add_ps(s, _mm_mul_ps(bot, _mm_sub_ps(one, sa)));
+        _mm_storeu_ps(dst, out);
+    }
+    len
+}
+
+/// Composite a color with a mask onto float Gray+alpha pixels (SSE4.1).
+///
+/// * `gray` Mask values converted to gray values.
+/// * `clr` Mask color value (associated) and alpha.
+///
+/// Returns the number of pixels blended (a multiple of 2).
+#[target_feature(enable = "sse4.1")]
+unsafe fn over_gray32_sse41(
+    pix: *mut f32,
+    mask: *const f32,
+    gray: &[f32],
+    len: usize,
+    clr: [f32; 2],
+) -> usize {
+    let c = _mm_set_ps(clr[1], clr[0], clr[1], clr[0]);
+    let one = _mm_set1_ps(1.0);
+    // Truncate len to multiple of 2
+    let len2 = (len >> 1) << 1;
+    // 2 pixels at a time
+    for i in (0..len2).step_by(2) {
+        let dst = pix.add(i * 2);
+        // interleave gray and mask values: 10, 10 => 1100
+        let m0 = *mask.add(i);
+        let m1 = *mask.add(i + 1);
+        let m = _mm_set_ps(m1, gray[i + 1], m0, gray[i]);
+        // Source: mask color * mask gray, mask alpha * mask value
+        let s = _mm_mul_ps(c, m);
+        // Source alpha, for each channel
+        let sa = _mm_shuffle_ps(s, s, 0b11_11_01_01);
+        // Over: s + b * (1 - sa)
+        let bot = _mm_loadu_ps(dst);
+        let out = _mm_add_ps(s, _mm_mul_ps(bot, _mm_sub_ps(one, sa)));
+        _mm_storeu_ps(dst, out);
+    }
+    len2
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fixture::{gray16, mask16, rgba16};
+    use crate::{Blend, Rounding};
+    use pix::*;
+
+    /// Make test float mask pixels.
+    fn mask32(len: usize) -> Vec<Mask32> {
+        mask16(len)
+            .iter()
+            .map(|m| Mask32::new(Ch32::from(m.alpha().value())))
+            .collect()
+    }
+
+    /// Make arbitrary associated colors (value channels at most alpha).
+    fn colors() -> impl Iterator<Item = [Ch16; 4]> {
+        let mut seed = 0x2F6B_u32;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 8) as u16
+        };
+        (0..8).map(move |_| {
+            let a = next();
+            let mut v = || next() % a.saturating_add(1);
+            [v(), v(), v(), a].map(Ch16::new)
+        })
+    }
+
+    /// Opacities to cycle through, including some below `MAX`.
+    const OPACITIES: [u16; 3] = [0xFFFF, 0xB3A1, 0x2C57];
+
+    /// Rounding policies to cycle through.
+    const ROUNDINGS: [Rounding; 2] = [Rounding::Fast, Rounding::Exact];
+
+    /// Make arbitrary associated colors, each with an opacity and rounding.
+    fn colors_opacity() -> impl Iterator<Item = ([Ch16; 4], Ch16, Rounding)> {
+        let o = OPACITIES.iter().cycle().map(|o| Ch16::new(*o));
+        let r = ROUNDINGS.iter().cycle();
+        colors().zip(o).zip(r).map(|((c, o), r)| (c, o, *r))
+    }
+
+    /// Check that pixel slices are equal.
+    fn assert_same<F: Format>(pix: &[F], fallback: &[F]) {
+        for (p, f) in pix.iter().zip(fallback) {
+            assert_eq!(p.rgba(), f.rgba());
+        }
+    }
+
+    #[test]
+    fn rgba16_matches_fallback() {
+        for len in 0..67 {
+            let m = mask16(len);
+            for (clr, o, rnd) in colors_opacity() {
+                let clr = AssocSRgba16::with_rgba(clr);
+                let mut dst = rgba16(len);
+                let mut fallback = dst.clone();
+                AssocSRgba16::over_slice(&mut dst, &m, clr, o, rnd);
+                AssocSRgba16::over_fallback(&mut fallback, &m, clr, o, rnd);
+                assert_same(&dst, &fallback);
+            }
+        }
+    }
+
+    #[test]
+    fn rgba32_matches_fallback() {
+        for len in 0..67 {
+            let m = mask32(len);
+            for (clr, o, rnd) in colors_opacity() {
+                let clr = AssocSRgba32::with_rgba(clr.map(Ch32::from));
+                let o = Ch32::from(o);
+                let mut dst: Vec<AssocSRgba32> = rgba16(len)
+                    .iter()
+                    .map(|p| AssocSRgba32::with_rgba(p.rgba().map(Ch32::from)))
+                    .collect();
+                let mut fallback = dst.clone();
+                AssocSRgba32::over_slice(&mut dst, &m, clr, o, rnd);
+                AssocSRgba32::over_fallback(&mut fallback, &m, clr, o, rnd);
+                assert_same(&dst, &fallback);
+            }
+        }
+    }
+
+    #[test]
+    fn gray_matches_fallback() {
+        for len in 0..67 {
+            for (clr, o16, rnd) in colors_opacity() {
+                let m = mask16(len);
+                let c = AssocSGrayAlpha16::with_alpha(clr[0], clr[3]);
+                let mut dst = gray16(len);
+                let mut slow = dst.clone();
+                AssocSGrayAlpha16::over_slice(&mut dst, &m, c, o16, rnd);
+                AssocSGrayAlpha16::over_fallback(&mut slow, &m, c, o16, rnd);
+                assert_same(&dst, &slow);
+                let m = mask32(len);
+                let c = AssocSGrayAlpha32::with_alpha(
+                    Ch32::from(clr[0]),
+                    Ch32::from(clr[3]),
+                );
+                let o32 = Ch32::from(o16);
+                let mut dst: Vec<AssocSGrayAlpha32> = gray16(len)
+                    .iter()
+                    .map(|p| {
+                        let (v, a) = (p.value(), p.alpha().value());
+                        Gray::with_alpha(Ch32::from(v), Ch32::from(a))
+                    })
+                    .collect();
+                let mut slow = dst.clone();
+                AssocSGrayAlpha32::over_slice(&mut dst, &m, c, o32, rnd);
+                AssocSGrayAlpha32::over_fallback(&mut slow, &m, c, o32, rnd);
+                assert_same(&dst, &slow);
+            }
+        }
+    }
+}
@@ -0,0 +1,558 @@
+// dither.rs    Dithering for channel depth reduction.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use crate::raster::{raster_rows_rect, Parallel};
+use crate::{raster_lerp, Blend, BlendMode, Placement, Rect, Rounding};
+use pix::{
+    AlphaMode, AlphaModeID, Ch32, Ch8, Channel, Format, GammaMode, Gray, Mask,
+    Opaque, Raster, RasterBuilder, Rgb, Translucent,
+};
+use std::sync::OnceLock;
+
+/// Size of blue noise threshold map
+const BLUE_NOISE_SIZE: usize = 32;
+
+/// Dithering method for reducing channels to 8 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+    /// Ordered dithering with an 8x8 Bayer matrix
+    Bayer,
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+    /// Ordered dithering with a 32x32 blue noise threshold map
+    BlueNoise,
+}
+
+/// 8-bit pixel format which can be dithered from 32-bit channels.
+pub trait DitherFormat: Format<Chan = Ch8> {
+    /// Same format, with 32-bit channels
+    type Deep: Blend + Format<Chan = Ch32>;
+}
+
+impl<M, G> DitherFormat for Rgb<Ch8, Opaque<Ch8>, M, G>
+where
+    M: AlphaMode,
+    G: GammaMode,
+{
+    type Deep = Rgb<Ch32, Opaque<Ch32>, M, G>;
+}
+
+impl<M, G> DitherFormat for Rgb<Ch8, Translucent<Ch8>, M, G>
+where
+    M: AlphaMode,
+    G: GammaMode,
+{
+    type Deep = Rgb<Ch32, Translucent<Ch32>, M, G>;
+}
+
+impl<M, G> DitherFormat for Gray<Ch8, Opaque<Ch8>, M, G>
+where
+    M: AlphaMode,
+    G: GammaMode,
+{
+    type Deep = Gray<Ch32, Opaque<Ch32>, M, G>;
+}
+
+impl<M, G> DitherFormat for Gray<Ch8, Translucent<Ch8>, M, G>
+where
+    M: AlphaMode,
+    G: GammaMode,
+{
+    type Deep = Gray<Ch32, Translucent<Ch32>, M, G>;
+}
+
+impl DitherFormat for Mask<Translucent<Ch8>> {
+    type Deep = Mask<Translucent<Ch32>>;
+}
+
+/// Quantizer for rows of pixels
+pub(crate) struct Quantizer {
+    /// Dithering method
+    method: Dither,
+    /// Distance between quantized values
+    spread: f32,
+    /// Size of threshold map
+    size: usize,
+    /// Threshold map for ordered dithering
+    map: &'static [f32],
+    /// Error diffused to current row (offset by one pixel)
+    err: Vec<[f32; 4]>,
+    /// Error diffused to next row (offset by one pixel)
+    next: Vec<[f32; 4]>,
+}
+
+/// Pattern of pixels for making a blue noise threshold map
+#[derive(Clone)]
+struct Pattern {
+    /// Width and height
+    size: usize,
+    /// Gaussian weights for each offset, wrapped around edges
+    weight: Vec<f32>,
+    /// Pixels in pattern
+    on: Vec<bool>,
+    /// Weighted density of pattern at each pixel
+    energy: Vec<f32>,
+}
+
+impl Pattern {
+    /// Create an empty pattern
+    fn new(size: usize) -> Self {
+        let len = size * size;
+        let sigma = 1.5;
+        let weight = (0..len)
+            .map(|i| {
+                let (dx, dy) = (i % size, i / size);
+                let (dx, dy) = (dx.min(size - dx), dy.min(size - dy));
+                let d = (dx * dx + dy * dy) as f32;
+                (-d / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        Pattern {
+            size,
+            weight,
+            on: vec![false; len],
+            energy: vec![0.0; len],
+        }
+    }
+
+    /// Add or remove one pixel
+    fn toggle(&mut self, i: usize) {
+        let n = self.size;
+        let (x, y) = (i % n, i / n);
+        self.on[i] = !self.on[i];
+        let s = if self.on[i] { 1.0 } else { -1.0 };
+        for (j, e) in self.energy.iter_mut().enumerate() {
+            let dx = (j % n + n - x) % n;
+            let dy = (j / n + n - y) % n;
+            *e += s * self.weight[dy * n + dx];
+        }
+    }
+
+    /// Find the tightest cluster (pixel in pattern with most energy)
+    fn tightest(&self) -> usize {
+        self.find(true, |e, best| e > best)
+    }
+
+    /// Find the largest void (pixel not in pattern with least energy)
+    fn largest_void(&self) -> usize {
+        self.find(false, |e, best| e < best)
+    }
+
+    /// Find the best pixel which is in (or not in) the pattern
+    fn find<F>(&self, on: bool, better: F) -> usize
+    where
+        F: Fn(f32, f32) -> bool,
+    {
+        let mut best: Option<usize> = None;
+        for (i, e) in self.energy.iter().enumerate() {
+            if self.on[i] == on
+                && best.map_or(true, |b| better(*e, self.energy[b]))
+            {
+                best = Some(i);
+            }
+        }
+        best.unwrap_or(0)
+    }
+}
+
+impl Quantizer {
+    /// Create a new quantizer
+    ///
+    /// * `method` Dithering method.
+    /// * `width` Width of rows.
+    /// * `spread` Distance between quantized values, in 8-bit range.
+    pub(crate) fn new(method: Dither, width: usize, spread: f32) -> Self {
+        static BAYER: OnceLock<Vec<f32>> = OnceLock::new();
+        static BLUE_NOISE: OnceLock<Vec<f32>> = OnceLock::new();
+        // Threshold maps are made once, since blue noise is slow
+        let (size, map): (usize, &'static [f32]) = match method {
+            Dither::Bayer => (8, BAYER.get_or_init(bayer)),
+            Dither::FloydSteinberg => (1, &[0.5]),
+            Dither::BlueNoise => (
+                BLUE_NOISE_SIZE,
+                BLUE_NOISE.get_or_init(|| blue_noise(BLUE_NOISE_SIZE)),
+            ),
+        };
+        Quantizer {
+            method,
+            spread,
+            size,
+            map,
+            err: vec![[0.0; 4]; width + 2],
+            next: vec![[0.0; 4]; width + 2],
+        }
+    }
+
+    /// Quantize one row of pixels
+    ///
+    /// * `dst` Destination row.
+    /// * `src` Source channels, scaled to 8-bit range.
+    /// * `x` Column of first pixel.
+    /// * `y` Row number.
+    /// * `nearest` Function to get the nearest quantized value, and its
+    ///   channels.
+    pub(crate) fn row<T, Q>(
+        &mut self,
+        dst: &mut [T],
+        src: &[[f32; 4]],
+        x: usize,
+        y: usize,
+        nearest: Q,
+    ) where
+        Q: Fn([f32; 4]) -> (T, [f32; 4]),
+    {
+        if self.method == Dither::FloydSteinberg {
+            self.diffuse(dst, src, nearest);
+            return;
+        }
+        let n = self.size;
+        let map = &self.map[(y % n) * n..][..n];
+        for (i, (d, s)) in dst.iter_mut().zip(src).enumerate() {
+            let t = (map[(x + i) % n] - 0.5) * self.spread;
+            *d = nearest(s.map(|v| v + t)).0;
+        }
+    }
+
+    /// Quantize one row of pixels with error diffusion
+    fn diffuse<T, Q>(&mut self, dst: &mut [T], src: &[[f32; 4]], nearest: Q)
+    where
+        Q: Fn([f32; 4]) -> (T, [f32; 4]),
+    {
+        std::mem::swap(&mut self.err, &mut self.next);
+        self.next.iter_mut().for_each(|e| *e = [0.0; 4]);
+        for (i, (d, s)) in dst.iter_mut().zip(src).enumerate() {
+            let mut v = *s;
+            for (v, e) in v.iter_mut().zip(&self.err[i + 1]) {
+                *v += e;
+            }
+            let (p, q) = nearest(v);
+            *d = p;
+            for c in 0..4 {
+                let e = v[c] - q[c];
+                self.err[i + 2][c] += e * 7.0 / 16.0;
+                self.next[i][c] += e * 3.0 / 16.0;
+                self.next[i + 1][c] += e * 5.0 / 16.0;
+                self.next[i + 2][c] += e / 16.0;
+            }
+        }
+    }
+}
+
+/// Get channels of a deep pixel, scaled to 8-bit range.
+fn channels<F: Format<Chan = Ch32>>(p: F) -> [f32; 4] {
+    p.rgba().map(|c| f32::from(c) * 255.0)
+}
+
+/// Make an 8-bit pixel from channels, clamping to range.
+///
+/// Associated color channels are also clamped to alpha.
+fn pixel<A: DitherFormat>(v: [f32; 4]) -> A {
+    let mut rgba = v.map(|v| Ch8::new(v.clamp(0.0, 255.0) as u8));
+    if <A as AlphaMode>::ID == AlphaModeID::Associated {
+        let a = rgba[3];
+        rgba[..3].iter_mut().for_each(|c| *c = (*c).min(a));
+    }
+    A::with_rgba(rgba)
+}
+
+/// Make an 8x8 Bayer threshold map.
+fn bayer() -> Vec<f32> {
+    (0..64)
+        .map(|i| {
+            let (x, y) = (i % 8, i / 8);
+            let mut v = 0;
+            for b in 0..3 {
+                let (xb, yb) = ((x >> b) & 1, (y >> b) & 1);
+                v |= ((xb ^ yb) << 1 | yb) << (2 * (2 - b));
+            }
+            (v as f32 + 0.5) / 64.0
+        })
+        .collect()
+}
+
+/// Make a blue noise threshold map with the void-and-cluster method.
+///
+/// Pixels are ranked by the order they are added to a pattern, filling the
+/// largest void each time.  Voids are found with a Gaussian filter which
+/// wraps around the edges, so the map can be tiled.
+///
+/// * `size` Width and height of map.
+fn blue_noise(size: usize) -> Vec<f32> {
+    let len = size * size;
+    let mut pattern = Pattern::new(size);
+    // Start with a sparse pseudo-random pattern
+    for i in 0..len {
+        let h = (i as u32 ^ 0x5BD1_E995).wrapping_mul(0x9E37_79B9);
+        if h ^ (h >> 15) < u32::MAX / 10 {
+            pattern.toggle(i);
+        }
+    }
+    // Move tightest clusters into largest voids until stable
+    for _ in 0..len {
+        let cluster = pattern.tightest();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+    let mut rank = vec![0; len];
+    let ones = pattern.on.iter().filter(|on| **on).count();
+    // Rank starting pattern by removing tightest clusters
+    let mut p = pattern.clone();
+    for r in (0..ones).rev() {
+        let cluster = p.tightest();
+        p.toggle(cluster);
+        rank[cluster] = r;
+    }
+    // Rank remaining pixels by filling largest voids
+    for r in ones..len {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        rank[void] = r;
+    }
+    rank.iter().map(|r| (*r as f32 + 0.5) / len as f32).collect()
+}
+
+/// Quantize deep pixels to a rectangle of an 8-bit raster.
+///
+/// Pixels outside the rectangle are not affected, even by error diffusion.
+///
+/// * `dst` Destination raster.
+/// * `src` Source pixels, in row-major order (same size as `dst`).
+/// * `rect` Rectangle to quantize (within `dst`).
+/// * `method` Dithering method.
+fn quantize<A>(
+    dst: &mut Raster<A>,
+    src: &[A::Deep],
+    rect: Rect,
+    method: Dither,
+) where
+    A: DitherFormat,
+{
+    let w = dst.width() as usize;
+    let (x, y) = (rect.x as usize, rect.y as usize);
+    let (rw, rh) = (rect.width as usize, rect.height as usize);
+    if rw > 0 {
+        let mut q = Quantizer::new(method, rw, 1.0);
+        let rows = dst.as_slice_mut().chunks_mut(w).zip(src.chunks(w));
+        for (y, (d, s)) in rows.enumerate().skip(y).take(rh) {
+            let (d, s) = (&mut d[x..x + rw], &s[x..x + rw]);
+            let v: Vec<_> = s.iter().map(|p| channels(*p)).collect();
+            q.row(d, &v, x, y, |v| {
+                let p: A = pixel(v.map(f32::round));
+                (p, p.rgba().map(|c| f32::from(u8::from(c))))
+            });
+        }
+    }
+}
+
+/// Convert a raster to 32-bit channels of an 8-bit format.
+fn deepen<A, B>(src: &Raster<B>) -> Raster<A::Deep>
+where
+    A: DitherFormat,
+    B: Format,
+    Ch32: From<B::Chan>,
+{
+    let pixels: Vec<A::Deep> =
+        src.as_slice().iter().map(|p| p.convert()).collect();
+    RasterBuilder::new().with_pixels(src.width(), src.height(), pixels)
+}
+
+/// Convert a raster to an 8-bit format, with dithering.
+///
+/// Alpha mode and gamma are converted first, using 32-bit channels, so
+/// gradients from deep rasters do not show banding.
+///
+/// * `src` Source raster.
+/// * `method` Dithering method.
+///
+/// ```
+/// # use pix::*;
+/// # use pixops::*;
+/// let r = RasterBuilder::<SepSRgb16>::new().with_clear(4, 4);
+/// let r: Raster<SepSRgb8> = dither(&r, Dither::Bayer);
+/// assert_eq!(r.width(), 4);
+/// ```
+pub fn dither<A, B>(src: &Raster<B>, method: Dither) -> Raster<A>
+where
+    A: DitherFormat,
+    B: Format,
+    Ch32: From<B::Chan>,
+{
+    let deep = deepen::<A, B>(src);
+    let mut dst = RasterBuilder::new().with_clear(src.width(), src.height());
+    let rect = Rect::from(&dst);
+    quantize(&mut dst, deep.as_slice(), rect, method);
+    dst
+}
+
+/// Interpolate linearly between two targets, dithering to an 8-bit target.
+///
+/// Interpolation is done with 32-bit channels, so slow cross-fades do not
+/// show banding.
+///
+/// * `dst` Destination target.
+/// * `a` First source target.
+/// * `b` Second source target.
+/// * `t` Interpolation from `a` (`MIN`) to `b` (`MAX`).
+/// * `method` Dithering method.
+///
+/// # Panics
+///
+/// Panics if the targets are not all the same size.
+pub fn raster_lerp_dither<A, B, H>(
+    dst: &mut Raster<A>,
+    a: &Raster<B>,
+    b: &Raster<B>,
+    t: H,
+    method: Dither,
+) where
+    A: DitherFormat,
+    B: Format,
+    H: Channel,
+    Ch32: From<B::Chan> + From<H>,
+{
+    let (a, b) = (deepen::<A, B>(a), deepen::<A, B>(b));
+    let mut deep = RasterBuilder::new().with_clear(dst.width(), dst.height());
+    raster_lerp(&mut deep, &a, &b, Ch32::from(t), Ch32::MAX);
+    let rect = Rect::from(&*dst);
+    quantize(dst, deep.as_slice(), rect, method);
+}
+
+/// Blend targets with a blend mode, dithering to an 8-bit target.
+///
+/// Blending is done with 32-bit channels, so soft masks and gradients do not
+/// show banding.  Use `BlendMode::Normal` for `over` operation.  Only the
+/// affected destination rectangle is dithered.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `clr` Default blend color, with 32-bit channels.
+/// * `mode` Blend mode.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `place` Placement of source rectangle on destination.
+/// * `method` Dithering method.
+///
+/// Returns the affected destination rectangle, or `None` if the source
+/// rectangle is positioned off an edge.
+pub fn raster_blend_dither<A, B, C, H, P>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    clr: C,
+    mode: BlendMode,
+    opacity: H,
+    place: P,
+    method: Dither,
+) -> Option<Rect>
+where
+    A: DitherFormat,
+    A::Deep: From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    Ch32: From<H>,
+    H: Channel + From<Ch32> + Parallel,
+    P: Into<Placement>,
+{
+    let clr: A::Deep = clr.into();
+    let mut deep = deepen::<A, A>(dst);
+    let clip = Rect::from(&deep);
+    // 32-bit channels are not affected by the rounding policy
+    let rect = raster_rows_rect(&mut deep, clip, src, place.into(), |d, s| {
+        A::Deep::blend_slice(d, s, clr, mode, opacity, Rounding::Fast)
+    });
+    if let Some(rect) = rect {
+        quantize(dst, deep.as_slice(), rect, method);
+    }
+    rect
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pix::*;
+
+    #[test]
+    fn threshold_maps() {
+        let sorted = |map: Vec<f32>| {
+            let mut v: Vec<_> =
+                map.iter().map(|t| (t * map.len() as f32) as usize).collect();
+            v.sort_unstable();
+            v
+        };
+        let m = bayer();
+        let row: Vec<_> = m[..8].iter().map(|t| (t * 64.0) as u8).collect();
+        assert_eq!(row, [0, 32, 8, 40, 2, 34, 10, 42]);
+        assert_eq!(sorted(m), (0..64).collect::<Vec<_>>());
+        let m = blue_noise(BLUE_NOISE_SIZE);
+        assert_eq!(sorted(m), (0..1024).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn average() {
+        // Nearly halfway between 0x80 and 0x81
+        let v = Ch16::new(0x8100);
+        let r = RasterBuilder::<SepSGray16>::new()
+            .with_color(32, 32, SepSGray16::new(v));
+        for method in [Dither::Bayer, Dither::FloydSteinberg, Dither::BlueNoise]
+        {
+            let d: Raster<SepSGray8> = dither(&r, method);
+            let s = d.as_u8_slice();
+            assert!(s.iter().all(|v| *v == 0x80 || *v == 0x81));
+            let high = s.iter().filter(|v| **v == 0x81).count();
+            assert!(high > 480 && high < 544, "{:?} {}", method, high);
+        }
+        let r = RasterBuilder::<SepSRgba16>::new().with_clear(8, 8);
+        let d: Raster<AssocSRgba8> = dither(&r, Dither::Bayer);
+        assert_eq!(d.as_u8_slice(), &[0; 256][..]);
+    }
+
+    #[test]
+    fn lerp_blend() {
+        let a = RasterBuilder::<SepLGray16>::new().with_clear(16, 16);
+        let b = RasterBuilder::<SepLGray16>::new()
+            .with_color(16, 16, SepLGray16::new(Ch16::new(0x0202)));
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(16, 16);
+        raster_lerp_dither(&mut r, &a, &b, Ch16::new(0x8000), Dither::Bayer);
+        let s = r.as_u8_slice();
+        assert_eq!(s.iter().filter(|v| **v == 0x01).count(), 256);
+        raster_lerp_dither(&mut r, &a, &b, Ch16::new(0x4000), Dither::Bayer);
+        let s = r.as_u8_slice();
+        assert_eq!(s.iter().filter(|v| **v == 0x01).count(), 128);
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(16, 16);
+        let m = RasterBuilder::<Mask16>::new()
+            .with_color(8, 8, Mask16::new(Ch16::new(0x0080)));
+        let c = SepLGray32::new(Ch32::MAX);
+        let o = Ch16::MAX;
+        let n = BlendMode::Normal;
+        let p = (&m, 0, 0);
+        raster_blend_dither(&mut r, &m, c, n, o, p, Dither::BlueNoise);
+        let s = r.as_u8_slice();
+        assert_eq!(s.iter().filter(|v| **v > 0x01).count(), 0);
+        let ones = s.iter().filter(|v| **v == 0x01).count();
+        assert!(ones > 16 && ones < 48, "{}", ones);
+    }
+
+    #[test]
+    fn blend_rect() {
+        let pix: Vec<_> =
+            (0..256).map(|i| SepLGray8::new((i * 7 % 256) as u8)).collect();
+        let mut r = RasterBuilder::new().with_pixels(16, 16, pix.clone());
+        let m = RasterBuilder::<Mask16>::new()
+            .with_color(8, 8, Mask16::new(Ch16::new(0x8080)));
+        let c = SepLGray32::new(Ch32::MAX);
+        let (o, n) = (Ch16::MAX, BlendMode::Normal);
+        let d = Dither::FloydSteinberg;
+        let rect = raster_blend_dither(&mut r, &m, c, n, o, (&m, 4, 4), d);
+        assert_eq!(rect, Some(Rect::new(4, 4, 8, 8)));
+        for (i, p) in r.as_slice().iter().enumerate() {
+            let (x, y) = (i % 16, i / 16);
+            if !(4..12).contains(&x) || !(4..12).contains(&y) {
+                assert_eq!(*p, pix[i]);
+            }
+        }
+        let rect = raster_blend_dither(&mut r, &m, c, n, o, (&m, 16, 0), d);
+        assert_eq!(rect, None);
+    }
+}
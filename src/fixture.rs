@@ -0,0 +1,69 @@
+// fixture.rs   Test fixtures.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use pix::{
+    AssocSGrayAlpha16, AssocSGrayAlpha8, AssocSRgba16, AssocSRgba8, Mask16,
+    Mask8,
+};
+
+/// Make test mask values, from zero to `max`.
+fn mask(len: usize, max: usize) -> impl Iterator<Item = usize> {
+    (0..len).map(move |i| i * 2845 % (max + 1))
+}
+
+/// Make test destination values (color, alpha), from zero to `max`.
+///
+/// Color is never more than alpha, so the values are valid for associated
+/// pixels.
+fn values(len: usize, max: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..len).map(move |i| {
+        let a = i * 3621 % (max + 1);
+        let v = i * 1035 % (max + 1);
+        (v.min(a), a)
+    })
+}
+
+/// Make test 8-bit mask pixels.
+pub(crate) fn mask8(len: usize) -> Vec<Mask8> {
+    mask(len, 0xFF).map(|m| Mask8::new(m as u8)).collect()
+}
+
+/// Make test 16-bit mask pixels.
+pub(crate) fn mask16(len: usize) -> Vec<Mask16> {
+    mask(len, 0xFFFF).map(|m| Mask16::new(m as u16)).collect()
+}
+
+/// Make test associated 8-bit RGBA pixels.
+pub(crate) fn rgba8(len: usize) -> Vec<AssocSRgba8> {
+    values(len, 0xFF)
+        .map(|(v, a)| {
+            let (v, a) = (v as u8, a as u8);
+            AssocSRgba8::with_alpha(v, a / 2, a / 3, a)
+        })
+        .collect()
+}
+
+/// Make test associated 16-bit RGBA pixels.
+pub(crate) fn rgba16(len: usize) -> Vec<AssocSRgba16> {
+    values(len, 0xFFFF)
+        .map(|(v, a)| {
+            let (v, a) = (v as u16, a as u16);
+            AssocSRgba16::with_alpha(v, a / 2, a / 3, a)
+        })
+        .collect()
+}
+
+/// Make test associated 8-bit Gray+alpha pixels.
+pub(crate) fn gray8(len: usize) -> Vec<AssocSGrayAlpha8> {
+    values(len, 0xFF)
+        .map(|(v, a)| AssocSGrayAlpha8::with_alpha(v as u8, a as u8))
+        .collect()
+}
+
+/// Make test associated 16-bit Gray+alpha pixels.
+pub(crate) fn gray16(len: usize) -> Vec<AssocSGrayAlpha16> {
+    values(len, 0xFFFF)
+        .map(|(v, a)| AssocSGrayAlpha16::with_alpha(v as u16, a as u16))
+        .collect()
+}
@@ -3,26 +3,78 @@
 // Copyright (c) 2018-2019  Douglas P Lau
 // Copyright (c) 2020  Jeron Aldaron Lau
 //
-use crate::Blend;
+use crate::blend::{
+    mul, scale, Blend, BlendMode, PorterDuff, Rounding,
+};
+use crate::lerp::{Lerp, LerpAlpha};
 use pix::{
-    Alpha, AlphaMode, AssocGray, Channel, Format, GammaMode, Gray, Translucent,
+    Alpha, AlphaMode, AssocGray, Ch32, Channel, Format, GammaMode, Gray,
+    Translucent,
 };
 
 impl<C, A, M, G> Blend for Gray<C, A, M, G>
 where
-    C: Channel,
-    A: Alpha<Chan = C>,
+    C: Channel + Lerp,
+    A: Alpha<Chan = C> + LerpAlpha,
     A: From<C>,
     M: AlphaMode,
     G: GammaMode,
 {
+    /// Blend pixels with `over` operation.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_slice<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            feature = "simd"
+        ))]
+        let n = crate::deep::over_gray_slice(dst, src, clr, opacity, rnd);
+        #[cfg(all(target_arch = "aarch64", feature = "simd"))]
+        let n = crate::neon::over_slice(dst, src, clr, opacity, rnd);
+        #[cfg(not(all(
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "aarch64"
+            ),
+            feature = "simd"
+        )))]
+        let n = 0;
+        let len = dst.len().min(src.len());
+        if len > n {
+            Self::over_fallback(&mut dst[n..], &src[n..], clr, opacity, rnd);
+        }
+    }
+
     /// Blend pixels with `over` operation (slow fallback).
     ///
     /// * `dst` Destination pixels.
     /// * `src` Source pixels.
     /// * `clr` Mask color.
-    fn over_fallback<B, H>(dst: &mut [Self], src: &[B], clr: Self)
-    where
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
         B: Format<Chan = H>,
         C: From<H>,
         H: Channel,
@@ -31,32 +83,307 @@ where
         // Over operation requires alpha is Associated, translucency needed for
         // blending.
         let clr: AssocGray<H, Translucent<H>, G> = clr.convert();
+        let clr = with_opacity(clr, opacity, rnd);
 
         for (bot, top) in dst.iter_mut().zip(src) {
             // Apply mask color to source raster.
             let src: AssocGray<H, Translucent<H>, G> = top.convert();
-            let src = clr * src;
+            let src = mask_product(clr, src, rnd);
+
+            // Over Operation
+            *bot = over_pixel(*bot, src, rnd);
+        }
+    }
+
+    /// Blend pixels with `over` operation, clipped by a mask (slow
+    /// fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `mask` Mask pixels (alpha is coverage of source).
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_masked_fallback<B, N, H>(
+        dst: &mut [Self],
+        src: &[B],
+        mask: &[N],
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        N: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        for ((bot, top), m) in dst.iter_mut().zip(src).zip(mask) {
+            // Scale source raster by mask coverage.
+            let src: AssocGray<H, Translucent<H>, G> = top.convert();
+            let m = scale(m.rgba()[3], opacity, rnd);
+            let v = mul(src.value(), m, rnd);
+            let a = mul(src.alpha().value(), m, rnd);
+            let src =
+                AssocGray::<H, Translucent<H>, G>::with_rgba([v, v, v, a]);
 
             // Over Operation
-            *bot = Self::over(*bot, src);
+            *bot = over_pixel(*bot, src, rnd);
         }
     }
 
     /// Blend pixel on top of another, using "over".
-    fn over<B, H>(dst: Self, src: B) -> Self
+    fn over<B, H>(dst: Self, src: B, rnd: Rounding) -> Self
     where
         B: Format<Chan = H>,
         Self::Chan: From<H>,
         H: Channel,
         H: From<C>,
+    {
+        let src: AssocGray<H, Translucent<H>, G> = src.convert();
+
+        over_pixel(dst, src, rnd)
+    }
+
+    /// Composite pixels with a Porter-Duff operation (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `op` Compositing operation.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn composite_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        op: PorterDuff,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        let clr: AssocGray<H, Translucent<H>, G> = clr.convert();
+        let clr = with_opacity(clr, opacity, rnd);
+
+        for (bot, top) in dst.iter_mut().zip(src) {
+            // Apply mask color to source raster.
+            let src: AssocGray<H, Translucent<H>, G> = top.convert();
+            let src = mask_product(clr, src, rnd);
+
+            *bot = composite_pixel(*bot, src, op, rnd);
+        }
+    }
+
+    /// Composite one pixel with another, using a Porter-Duff operation.
+    fn composite<B, H>(
+        dst: Self,
+        src: B,
+        op: PorterDuff,
+        rnd: Rounding,
+    ) -> Self
+    where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        let src: AssocGray<H, Translucent<H>, G> = src.convert();
+
+        composite_pixel(dst, src, op, rnd)
+    }
+
+    /// Blend pixels with a blend mode (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `mode` Blend mode.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn blend_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        mode: BlendMode,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+        H: From<Ch32>,
+        Ch32: From<H>,
+    {
+        let clr: AssocGray<H, Translucent<H>, G> = clr.convert();
+        let clr = with_opacity(clr, opacity, rnd);
+
+        for (bot, top) in dst.iter_mut().zip(src) {
+            // Apply mask color to source raster.
+            let src: AssocGray<H, Translucent<H>, G> = top.convert();
+            let src = mask_product(clr, src, rnd);
+
+            *bot = Self::blend(*bot, src, mode);
+        }
+    }
+
+    /// Blend pixel on top of another, using a blend mode.
+    ///
+    /// Blending is done with 32-bit floating point channels.  Non-separable
+    /// modes use the destination value (`Hue`, `Saturation` and `Color`) or
+    /// the source value (`Luminosity`), since gray has no hue or saturation.
+    fn blend<B, H>(dst: Self, src: B, mode: BlendMode) -> Self
+    where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+        H: From<Ch32>,
+        Ch32: From<H>,
     {
         let dst: AssocGray<H, Translucent<H>, G> = dst.convert();
         let src: AssocGray<H, Translucent<H>, G> = src.convert();
+        let dst: AssocGray<Ch32, Translucent<Ch32>, G> = dst.convert();
+        let src: AssocGray<Ch32, Translucent<Ch32>, G> = src.convert();
+
+        let sa = f32::from(src.alpha().value());
+        let da = f32::from(dst.alpha().value());
+        let a = sa + da - sa * da;
+        let v = mode.blend_channel(
+            f32::from(src.value()),
+            sa,
+            f32::from(dst.value()),
+            da,
+        );
+
+        let v = Ch32::new(v);
+        let p = AssocGray::<Ch32, Translucent<Ch32>, G>::with_rgba([
+            v,
+            v,
+            v,
+            Ch32::new(a),
+        ]);
+        let p: AssocGray<H, Translucent<H>, G> = p.convert();
+        p.convert()
+    }
+
+    /// Interpolate one pixel linearly with another.
+    ///
+    /// Interpolation is done with associated alpha, so values of translucent
+    /// pixels are weighted by alpha.
+    fn lerp(a: Self, b: Self, t: C) -> Self {
+        let a: AssocGray<C, Translucent<C>, G> = a.convert();
+        let b: AssocGray<C, Translucent<C>, G> = b.convert();
+
+        AssocGray::<C, Translucent<C>, G>::with_alpha(
+            a.value().lerp(b.value(), t),
+            LerpAlpha::lerp(a.alpha(), b.alpha(), t),
+        )
+        .convert()
+    }
+}
+
+/// Blend pixel on top of another, using "over".
+///
+/// * `dst` Destination pixel.
+/// * `src` Associated source pixel.
+/// * `rnd` Rounding policy.
+fn over_pixel<F, H, G>(
+    dst: F,
+    src: AssocGray<H, Translucent<H>, G>,
+    rnd: Rounding,
+) -> F
+where
+    F: Format,
+    F::Chan: From<H>,
+    H: Channel + From<F::Chan>,
+    G: GammaMode,
+{
+    let dst: AssocGray<H, Translucent<H>, G> = dst.convert();
+
+    let one_minus_src_a = H::MAX - src.alpha().value();
+    let a = src.alpha().value()
+        + mul(dst.alpha().value(), one_minus_src_a, rnd);
+    let v = src.value() + mul(dst.value(), one_minus_src_a, rnd);
+
+    AssocGray::<F::Chan, Translucent<F::Chan>, G>::with_alpha(v, a).convert()
+}
+
+/// Composite one pixel with another, using a Porter-Duff operation.
+///
+/// * `dst` Destination pixel.
+/// * `src` Associated source pixel.
+/// * `op` Compositing operation.
+/// * `rnd` Rounding policy.
+fn composite_pixel<F, H, G>(
+    dst: F,
+    src: AssocGray<H, Translucent<H>, G>,
+    op: PorterDuff,
+    rnd: Rounding,
+) -> F
+where
+    F: Format,
+    F::Chan: From<H>,
+    H: Channel + From<F::Chan>,
+    G: GammaMode,
+{
+    let dst: AssocGray<H, Translucent<H>, G> = dst.convert();
+
+    let (sa, da) = (src.alpha().value(), dst.alpha().value());
+    let (fa, fb) = op.factors(sa, da);
+    let a = scale(sa, fa, rnd) + scale(da, fb, rnd);
+    let v = scale(src.value(), fa, rnd) + scale(dst.value(), fb, rnd);
+
+    AssocGray::<F::Chan, Translucent<F::Chan>, G>::with_alpha(v, a).convert()
+}
+
+/// Apply opacity to a mask color.
+fn with_opacity<H, G>(
+    clr: AssocGray<H, Translucent<H>, G>,
+    opacity: H,
+    rnd: Rounding,
+) -> AssocGray<H, Translucent<H>, G>
+where
+    H: Channel,
+    G: GammaMode,
+{
+    AssocGray::with_alpha(
+        scale(clr.value(), opacity, rnd),
+        scale(clr.alpha().value(), opacity, rnd),
+    )
+}
+
+/// Apply a mask color to a source pixel, using a rounding policy.
+fn mask_product<H, G>(
+    clr: AssocGray<H, Translucent<H>, G>,
+    src: AssocGray<H, Translucent<H>, G>,
+    rnd: Rounding,
+) -> AssocGray<H, Translucent<H>, G>
+where
+    H: Channel,
+    G: GammaMode,
+{
+    AssocGray::with_alpha(
+        mul(clr.value(), src.value(), rnd),
+        mul(clr.alpha().value(), src.alpha().value(), rnd),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Blend;
+    use crate::BlendMode::*;
 
-        let one_minus_src_a = H::MAX - src.alpha().value();
-        let a = src.alpha().value() + dst.alpha().value() * one_minus_src_a;
-        let v = src.value() + dst.value() * one_minus_src_a;
+    #[test]
+    fn non_separable() {
+        let d = pix::AssocSGrayAlpha8::with_alpha(0x40, 0xFF);
+        let s = pix::AssocSGrayAlpha8::with_alpha(0xC0, 0xFF);
 
-        AssocGray::<C, Translucent<C>, G>::with_alpha(v, a).convert()
+        assert_eq!(Blend::blend(d, s, Hue), d);
+        assert_eq!(Blend::blend(d, s, Saturation), d);
+        assert_eq!(Blend::blend(d, s, Color), d);
+        assert_eq!(Blend::blend(d, s, Luminosity), s);
     }
 }
@@ -0,0 +1,319 @@
+// kernel.rs    Convolution kernels.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use crate::resize::{from_format, to_format, Pixel};
+use pix::{Ch32, Format, Raster, RasterBuilder};
+
+/// Handling of pixels beyond the edges of a raster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeMode {
+    /// Repeat the nearest edge pixel
+    Clamp,
+    /// Wrap around to the opposite edge
+    Wrap,
+    /// Mirror pixels at the edge (without repeating edge pixels)
+    Mirror,
+    /// Pixels beyond the edge are transparent
+    Transparent,
+}
+
+/// Weights of a convolution kernel
+#[derive(Clone, Debug, PartialEq)]
+enum Weights {
+    /// Full weights, in row-major order
+    Full(Vec<f32>),
+    /// Horizontal and vertical weights of a separable kernel
+    Separable(Vec<f32>, Vec<f32>),
+}
+
+/// A convolution kernel, with odd width and height.
+///
+/// ```
+/// # use pixops::Kernel;
+/// let k = Kernel::new(3, 1, &[0.25, 0.5, 0.25]);
+/// assert_eq!(k.width(), 3);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Kernel {
+    /// Width in pixels
+    width: usize,
+    /// Height in pixels
+    height: usize,
+    /// Kernel weights
+    weights: Weights,
+}
+
+impl EdgeMode {
+    /// Get the index of a pixel, or `None` if transparent.
+    ///
+    /// * `i` Unchecked index.
+    /// * `len` Number of pixels (not zero).
+    fn index(self, i: isize, len: usize) -> Option<usize> {
+        let n = len as isize;
+        if i >= 0 && i < n {
+            return Some(i as usize);
+        }
+        match self {
+            EdgeMode::Clamp => Some(i.max(0).min(n - 1) as usize),
+            EdgeMode::Wrap => Some(i.rem_euclid(n) as usize),
+            EdgeMode::Mirror if n > 1 => {
+                let m = i.rem_euclid(2 * (n - 1));
+                Some(if m < n { m } else { 2 * (n - 1) - m } as usize)
+            }
+            EdgeMode::Mirror => Some(0),
+            EdgeMode::Transparent => None,
+        }
+    }
+}
+
+impl Kernel {
+    /// Create a new kernel.
+    ///
+    /// * `width` Kernel width (odd).
+    /// * `height` Kernel height (odd).
+    /// * `weights` Weights in row-major order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `width` or `height` is even, or if the number of weights
+    /// does not match.
+    pub fn new(width: u32, height: u32, weights: &[f32]) -> Self {
+        let (width, height) = (width as usize, height as usize);
+        assert!(width % 2 == 1 && height % 2 == 1, "kernel size not odd");
+        assert_eq!(width * height, weights.len(), "kernel size mismatch");
+        Kernel {
+            width,
+            height,
+            weights: Weights::Full(weights.to_vec()),
+        }
+    }
+
+    /// Create a new separable kernel.
+    ///
+    /// Separable kernels are convolved with a horizontal pass followed by
+    /// a vertical pass, which is faster for large kernels.
+    ///
+    /// * `horiz` Horizontal weights (odd length).
+    /// * `vert` Vertical weights (odd length).
+    ///
+    /// # Panics
+    ///
+    /// Panics if either length is even.
+    pub fn new_separable(horiz: &[f32], vert: &[f32]) -> Self {
+        let (width, height) = (horiz.len(), vert.len());
+        assert!(width % 2 == 1 && height % 2 == 1, "kernel size not odd");
+        Kernel {
+            width,
+            height,
+            weights: Weights::Separable(horiz.to_vec(), vert.to_vec()),
+        }
+    }
+
+    /// Create a 3x3 sharpen kernel
+    pub fn sharpen() -> Self {
+        #[rustfmt::skip]
+        let w = [
+             0.0, -1.0,  0.0,
+            -1.0,  5.0, -1.0,
+             0.0, -1.0,  0.0,
+        ];
+        Kernel::new(3, 3, &w)
+    }
+
+    /// Create a 3x3 emboss kernel
+    pub fn emboss() -> Self {
+        #[rustfmt::skip]
+        let w = [
+            -2.0, -1.0,  0.0,
+            -1.0,  1.0,  1.0,
+             0.0,  1.0,  2.0,
+        ];
+        Kernel::new(3, 3, &w)
+    }
+
+    /// Create a 3x3 Laplacian edge detection kernel
+    pub fn edge_detect() -> Self {
+        #[rustfmt::skip]
+        let w = [
+            -1.0, -1.0, -1.0,
+            -1.0,  8.0, -1.0,
+            -1.0, -1.0, -1.0,
+        ];
+        Kernel::new(3, 3, &w)
+    }
+
+    /// Create a horizontal Sobel kernel (detects vertical edges)
+    pub fn sobel_x() -> Self {
+        Kernel::new_separable(&[-1.0, 0.0, 1.0], &[1.0, 2.0, 1.0])
+    }
+
+    /// Create a vertical Sobel kernel (detects horizontal edges)
+    pub fn sobel_y() -> Self {
+        Kernel::new_separable(&[1.0, 2.0, 1.0], &[-1.0, 0.0, 1.0])
+    }
+
+    /// Get the kernel width
+    pub fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    /// Get the kernel height
+    pub fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    /// Check if the kernel is separable
+    pub fn is_separable(&self) -> bool {
+        match self.weights {
+            Weights::Full(_) => false,
+            Weights::Separable(_, _) => true,
+        }
+    }
+}
+
+/// Get weights for alpha, which are normalized absolute values.
+///
+/// Kernels which sum to zero, like edge detection, would otherwise make
+/// opaque pixels transparent.
+fn alpha_weights(weights: &[f32]) -> Vec<f32> {
+    let sum: f32 = weights.iter().map(|w| w.abs()).sum();
+    let sum = if sum > 0.0 { sum } else { 1.0 };
+    weights.iter().map(|w| w.abs() / sum).collect()
+}
+
+/// Convolve a raster with a kernel.
+///
+/// Convolution is done with associated alpha and linear gamma, using 32-bit
+/// floating point channels.  Color channels are convolved with the kernel
+/// weights, and alpha with their normalized absolute values.  Results are
+/// clamped.
+///
+/// * `src` Source raster.
+/// * `kernel` Convolution kernel.
+/// * `edge` Handling of pixels beyond the edges.
+pub fn convolve<F>(
+    src: &Raster<F>,
+    kernel: &Kernel,
+    edge: EdgeMode,
+) -> Raster<F>
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    let w = src.width() as usize;
+    let h = src.height() as usize;
+    if w == 0 || h == 0 {
+        return RasterBuilder::new().with_clear(src.width(), src.height());
+    }
+    let pixels: Vec<Pixel> =
+        src.as_slice().iter().map(|p| from_format(*p)).collect();
+    let pixels = match &kernel.weights {
+        Weights::Full(k) => {
+            let dims = (w, h, kernel.width, kernel.height);
+            pass(&pixels, dims, k, edge)
+        }
+        Weights::Separable(kh, kv) => {
+            let tmp = pass(&pixels, (w, h, kh.len(), 1), kh, edge);
+            pass(&tmp, (w, h, 1, kv.len()), kv, edge)
+        }
+    };
+    let pixels: Vec<F> = pixels.into_iter().map(to_format).collect();
+    RasterBuilder::new().with_pixels(src.width(), src.height(), pixels)
+}
+
+/// Do one convolution pass.
+///
+/// * `pixels` Source pixels.
+/// * `dims` Width and height of pixels and kernel.
+/// * `weights` Kernel weights.
+/// * `edge` Handling of pixels beyond the edges.
+fn pass(
+    pixels: &[Pixel],
+    dims: (usize, usize, usize, usize),
+    weights: &[f32],
+    edge: EdgeMode,
+) -> Vec<Pixel> {
+    let (w, h, kw, kh) = dims;
+    let alpha = alpha_weights(weights);
+    let (rx, ry) = ((kw / 2) as isize, (kh / 2) as isize);
+    let mut out = Vec::with_capacity(w * h);
+    for y in 0..h as isize {
+        for x in 0..w as isize {
+            let mut p = [0.0; 4];
+            for ky in 0..kh {
+                let sy = match edge.index(y + ky as isize - ry, h) {
+                    Some(sy) => sy,
+                    None => continue,
+                };
+                for kx in 0..kw {
+                    let sx = match edge.index(x + kx as isize - rx, w) {
+                        Some(sx) => sx,
+                        None => continue,
+                    };
+                    let s = pixels[sy * w + sx];
+                    let k = weights[ky * kw + kx];
+                    p[0] += s[0] * k;
+                    p[1] += s[1] * k;
+                    p[2] += s[2] * k;
+                    p[3] += s[3] * alpha[ky * kw + kx];
+                }
+            }
+            out.push(p);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pix::*;
+
+    #[test]
+    fn edge_modes() {
+        let idx = |e: EdgeMode| -> Vec<_> {
+            (-2..6).map(|i| e.index(i, 4)).collect()
+        };
+        let some = |v: &[usize]| -> Vec<_> {
+            v.iter().map(|i| Some(*i)).collect()
+        };
+        assert_eq!(idx(EdgeMode::Clamp), some(&[0, 0, 0, 1, 2, 3, 3, 3]));
+        assert_eq!(idx(EdgeMode::Wrap), some(&[2, 3, 0, 1, 2, 3, 0, 1]));
+        assert_eq!(idx(EdgeMode::Mirror), some(&[2, 1, 0, 1, 2, 3, 2, 1]));
+        let v = idx(EdgeMode::Transparent);
+        assert_eq!(v[..2], [None, None]);
+        assert_eq!(v[2..6], some(&[0, 1, 2, 3])[..]);
+        assert_eq!(v[6..], [None, None]);
+    }
+
+    #[test]
+    fn identity() {
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(3, 2);
+        r.set_pixel(0, 0, SepSRgba8::new(0xFF, 0x80, 0x40));
+        r.set_pixel(2, 1, SepSRgba8::with_alpha(0x40, 0x80, 0xFF, 0x80));
+        let k = Kernel::new(3, 3, &[0., 0., 0., 0., 1., 0., 0., 0., 0.]);
+        let c = convolve(&r, &k, EdgeMode::Transparent);
+        assert_eq!(c.as_u8_slice(), r.as_u8_slice());
+    }
+
+    #[test]
+    fn sobel() {
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(4, 3);
+        for y in 0..3 {
+            r.set_pixel(2, y, SepLGray8::new(0x20));
+            r.set_pixel(3, y, SepLGray8::new(0x20));
+        }
+        let c = convolve(&r, &Kernel::sobel_x(), EdgeMode::Clamp);
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x80, 0x80, 0x00,
+            0x00, 0x80, 0x80, 0x00,
+            0x00, 0x80, 0x80, 0x00,
+        ];
+        assert_eq!(c.as_u8_slice(), &v[..]);
+        let c = convolve(&r, &Kernel::sobel_y(), EdgeMode::Clamp);
+        assert_eq!(c.as_u8_slice(), &[0; 12][..]);
+    }
+}
@@ -4,6 +4,11 @@
 //
 use pix::{Alpha, Ch16, Ch32, Ch8, Channel, Opaque, Translucent};
 
+/// Linear interpolation of `Channel`s.
+///
+/// The result is `self` when `t` is `MIN`, and `rhs` when `t` is `MAX`.
+/// Integer channels are always rounded to nearest, regardless of the
+/// [Rounding](enum.Rounding.html) policy.
 // NOTE: Lerp equation is: (1 - t) * v0 + t * v1
 //          Equivalent to: v0 + t * (v1 - v0)
 pub trait Lerp {
@@ -22,12 +27,18 @@ impl Lerp for Ch8 {
     }
 }
 
-/// Scale an i32 value by a u8 (for lerp)
+/// Scale an i32 value by a u8 (for lerp), rounding to nearest
 #[inline]
 fn scale_i32(t: u8, v: i32) -> i32 {
-    let c = v * i32::from(t);
-    // cheap alternative to divide by 255
-    ((c + 1) + (c >> 8)) >> 8
+    let c = v.abs() * i32::from(t) + 128;
+    // divide by 255, rounding exactly (c is less than 65536)
+    let r = (c + (c >> 8)) >> 8;
+    // round symmetrically, so that lerp(a, b, t) == lerp(b, a, MAX - t)
+    if v < 0 {
+        -r
+    } else {
+        r
+    }
 }
 
 impl Lerp for Ch16 {
@@ -41,12 +52,17 @@ impl Lerp for Ch16 {
     }
 }
 
-/// Scale an i64 value by a u16 (for lerp)
+/// Scale an i64 value by a u16 (for lerp), rounding to nearest
 #[inline]
 fn scale_i64(t: u16, v: i64) -> i64 {
-    let c = v * i64::from(t);
-    // cheap alternative to divide by 65535
-    ((c + 1) + (c >> 16)) >> 16
+    let c = v.abs() * i64::from(t) + 32768;
+    // divide by 65535, rounding exactly (c is less than 2^32)
+    let r = (c + (c >> 16)) >> 16;
+    if v < 0 {
+        -r
+    } else {
+        r
+    }
 }
 
 impl Lerp for Ch32 {
@@ -60,21 +76,70 @@ impl Lerp for Ch32 {
     }
 }
 
+/// Linear interpolation of `Alpha` components.
+///
+/// `Opaque` alpha is unchanged.
 pub trait LerpAlpha: Alpha {
     /// Linear interpolation
-    fn lerp(self, rhs: Self::Chan) -> Self;
+    fn lerp(self, rhs: Self, t: Self::Chan) -> Self;
 }
 
 impl<C: Channel + Lerp> LerpAlpha for Opaque<C> {
     /// Linear interpolation
-    fn lerp(self, _rhs: C) -> Self {
+    fn lerp(self, _rhs: Self, _t: C) -> Self {
         Opaque::default()
     }
 }
 
 impl<C: Channel + Lerp> LerpAlpha for Translucent<C> {
     /// Linear interpolation
-    fn lerp(self, rhs: C) -> Self {
-        Self::new(self.value().lerp(rhs, rhs))
+    fn lerp(self, rhs: Self, t: C) -> Self {
+        Self::new(self.value().lerp(rhs.value(), t))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn channels() {
+        let (a, b) = (Ch8::new(0x20), Ch8::new(0xE0));
+        assert_eq!(a.lerp(b, Ch8::MIN), a);
+        assert_eq!(a.lerp(b, Ch8::MAX), b);
+        assert_eq!(a.lerp(b, Ch8::new(0x80)), Ch8::new(0x80));
+        assert_eq!(b.lerp(a, Ch8::new(0x80)), Ch8::new(0x80));
+        let (a, b) = (Ch16::new(0x2000), Ch16::new(0xE000));
+        assert_eq!(a.lerp(b, Ch16::MIN), a);
+        assert_eq!(a.lerp(b, Ch16::MAX), b);
+        let (a, b) = (Ch32::new(0.25), Ch32::new(0.75));
+        assert_eq!(a.lerp(b, Ch32::new(0.5)), Ch32::new(0.5));
+    }
+
+    #[test]
+    fn alpha() {
+        let a = Translucent::new(Ch8::new(0x00));
+        let b = Translucent::new(Ch8::new(0xFF));
+        let t = Ch8::new(0x40);
+        assert_eq!(LerpAlpha::lerp(a, b, t), Translucent::new(t));
+        let o = Opaque::<Ch8>::default();
+        assert_eq!(LerpAlpha::lerp(o, o, t), o);
+    }
+    #[test]
+    fn round_nearest() {
+        for v in 0..=255u8 {
+            for t in 0..=255u8 {
+                let (a, b) = (Ch8::new(0), Ch8::new(v));
+                let r = (f32::from(v) * f32::from(t) / 255.0).round();
+                assert_eq!(a.lerp(b, Ch8::new(t)), Ch8::new(r as u8));
+            }
+        }
+        for v in (0..=65535u16).step_by(257) {
+            for t in (0..=65535u16).step_by(241) {
+                let (a, b) = (Ch16::new(0), Ch16::new(v));
+                let r = (f64::from(v) * f64::from(t) / 65535.0).round();
+                assert_eq!(a.lerp(b, Ch16::new(t)), Ch16::new(r as u16));
+            }
+        }
     }
 }
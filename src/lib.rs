@@ -5,11 +5,55 @@
 //! Pixel operations crate.
 //!
 mod blend;
+mod blur;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    feature = "simd"
+))]
+mod deep;
+mod dither;
+#[cfg(test)]
+mod fixture;
 mod gray;
+mod kernel;
 mod lerp;
+mod linear;
 mod mask;
+mod morph;
+#[cfg(all(target_arch = "aarch64", feature = "simd"))]
+mod neon;
+mod quantize;
 mod raster;
+mod rect;
+mod resize;
 mod rgb;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    feature = "simd"
+))]
+mod rgba;
+mod shadow;
+mod transform;
 
-pub use crate::blend::Blend;
-pub use crate::raster::raster_over;
+pub use crate::blend::{Blend, BlendMode, PorterDuff, Rounding};
+pub use crate::blur::{blur_box, blur_gaussian};
+pub use crate::dither::{
+    dither, raster_blend_dither, raster_lerp_dither, Dither, DitherFormat,
+};
+pub use crate::kernel::{convolve, EdgeMode, Kernel};
+pub use crate::lerp::{Lerp, LerpAlpha};
+pub use crate::mask::{invert_slice, mask_slice, MaskOp};
+pub use crate::morph::{close, dilate, erode, open, Structure};
+pub use crate::quantize::{quantize, remap, Quantize};
+pub use crate::raster::{
+    raster_blend, raster_composite, raster_invert, raster_lerp,
+    raster_lerp_masked, raster_mask_op, raster_over, raster_over_linear,
+    raster_over_masked, raster_over_rect, raster_over_subpixel,
+    raster_over_transformed,
+};
+pub use crate::rect::{Placement, Rect};
+pub use crate::resize::{resize, Filter};
+pub use crate::shadow::{drop_shadow, outer_glow};
+pub use crate::transform::Transform;
+#[cfg(feature = "rayon")]
+pub use crate::raster::{min_rows_per_task, set_min_rows_per_task};
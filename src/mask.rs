@@ -3,13 +3,16 @@
 // Copyright (c) 2019  Douglas P Lau
 // Copyright (c) 2020  Jeron Aldaron Lau
 //
-use crate::Blend;
-use pix::{Alpha, Channel, Format, Mask};
+use crate::blend::{
+    mul, scale, Blend, BlendMode, PorterDuff, Rounding,
+};
+use crate::lerp::{Lerp, LerpAlpha};
+use pix::{Alpha, Ch32, Channel, Format, Mask, Translucent};
 
 impl<C, A> Blend for Mask<A>
 where
-    C: Channel,
-    A: Alpha<Chan = C>,
+    C: Channel + Lerp,
+    A: Alpha<Chan = C> + LerpAlpha,
     A: From<C>,
 {
     /// Blend pixels with `over` operation (slow fallback).
@@ -17,25 +20,118 @@ where
     /// * `dst` Destination pixels.
     /// * `src` Source pixels.
     /// * `clr` Mask color.
-    fn over_fallback<B, H>(dst: &mut [Self], src: &[B], clr: Self)
-    where
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
         B: Format<Chan = H>,
         C: From<H>,
         H: Channel,
         H: From<C>,
     {
+        let clr = scale(clr.alpha().value(), C::from(opacity), rnd);
         for (bot, top) in dst.iter_mut().zip(src) {
             // Apply mask color to source raster.
             let src: Self = top.convert();
-            let src = clr * src;
+            let sa = mul(clr, src.alpha().value(), rnd);
 
             // Over Operation
-            *bot = Self::over::<Self, C>(*bot, src);
+            *bot = Self::new(over_alpha(bot.alpha().value(), sa, rnd));
+        }
+    }
+
+    /// Blend pixels with `over` operation, clipped by a mask (slow
+    /// fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `mask` Mask pixels (alpha is coverage of source).
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_masked_fallback<B, N, H>(
+        dst: &mut [Self],
+        src: &[B],
+        mask: &[N],
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        N: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        for ((bot, top), m) in dst.iter_mut().zip(src).zip(mask) {
+            // Scale source raster by mask coverage.
+            let src: Mask<Translucent<H>> = top.convert();
+            let m = scale(m.rgba()[3], opacity, rnd);
+            let a = mul(src.alpha().value(), m, rnd);
+            let src: Self = Mask::<Translucent<H>>::new(a).convert();
+
+            // Over Operation
+            let sa = src.alpha().value();
+            *bot = Self::new(over_alpha(bot.alpha().value(), sa, rnd));
         }
     }
 
     /// Blend pixel on top of another, using "over".
-    fn over<B, H>(dst: Self, src: B) -> Self
+    fn over<B, H>(dst: Self, src: B, rnd: Rounding) -> Self
+    where
+        B: Format<Chan = H>,
+        Self::Chan: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        let src: Self = src.convert();
+        let sa = src.alpha().value();
+
+        Self::new(over_alpha(dst.alpha().value(), sa, rnd))
+    }
+
+    /// Composite pixels with a Porter-Duff operation (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `op` Compositing operation.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn composite_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        op: PorterDuff,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        let clr = scale(clr.alpha().value(), C::from(opacity), rnd);
+        for (bot, top) in dst.iter_mut().zip(src) {
+            // Apply mask color to source raster.
+            let src: Self = top.convert();
+            let sa = mul(clr, src.alpha().value(), rnd);
+            let da = bot.alpha().value();
+
+            *bot = Self::new(composite_alpha(da, sa, op, rnd));
+        }
+    }
+
+    /// Composite one pixel with another, using a Porter-Duff operation.
+    fn composite<B, H>(
+        dst: Self,
+        src: B,
+        op: PorterDuff,
+        rnd: Rounding,
+    ) -> Self
     where
         B: Format<Chan = H>,
         Self::Chan: From<H>,
@@ -43,10 +139,178 @@ where
         H: From<C>,
     {
         let src: Self = src.convert();
+        let sa = src.alpha().value();
+        let da = dst.alpha().value();
 
-        let one_minus_src_a = C::MAX - src.alpha().value();
-        let a = src.alpha().value() + dst.alpha().value() * one_minus_src_a;
+        Self::new(composite_alpha(da, sa, op, rnd))
+    }
 
-        Self::new(a)
+    /// Blend pixels with a blend mode (slow fallback).
+    ///
+    /// Masks have no color, so this is the same as `over`.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `mode` Blend mode.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn blend_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        _mode: BlendMode,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+        H: From<Ch32>,
+        Ch32: From<H>,
+    {
+        Self::over_fallback(dst, src, clr, opacity, rnd);
+    }
+
+    /// Blend pixel on top of another, using a blend mode.
+    ///
+    /// Masks have no color, so this is the same as `over`.
+    fn blend<B, H>(dst: Self, src: B, _mode: BlendMode) -> Self
+    where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+        H: From<Ch32>,
+        Ch32: From<H>,
+    {
+        Self::over(dst, src, Rounding::Fast)
+    }
+
+    /// Interpolate one pixel linearly with another.
+    fn lerp(a: Self, b: Self, t: C) -> Self {
+        Mask::new(LerpAlpha::lerp(a.alpha(), b.alpha(), t))
+    }
+}
+
+/// Blend mask alpha on top of another, using "over".
+///
+/// * `da` Destination alpha.
+/// * `sa` Source alpha.
+/// * `rnd` Rounding policy.
+fn over_alpha<C: Channel>(da: C, sa: C, rnd: Rounding) -> C {
+    sa + mul(da, C::MAX - sa, rnd)
+}
+
+/// Composite mask alpha with another, using a Porter-Duff operation.
+///
+/// * `da` Destination alpha.
+/// * `sa` Source alpha.
+/// * `op` Compositing operation.
+/// * `rnd` Rounding policy.
+fn composite_alpha<C>(da: C, sa: C, op: PorterDuff, rnd: Rounding) -> C
+where
+    C: Channel,
+{
+    let (fa, fb) = op.factors(sa, da);
+    scale(sa, fa, rnd) + scale(da, fb, rnd)
+}
+
+/// Operation for combining two masks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskOp {
+    /// Maximum of both masks
+    Union,
+    /// Minimum of both masks
+    Intersection,
+    /// Product of both masks
+    Multiply,
+    /// Source subtracted from destination
+    Subtract,
+    /// Difference between masks
+    Xor,
+}
+
+impl MaskOp {
+    /// Combine two mask values
+    fn combine<C: Channel>(self, d: C, s: C, rnd: Rounding) -> C {
+        match self {
+            MaskOp::Union => d.max(s),
+            MaskOp::Intersection => d.min(s),
+            MaskOp::Multiply => mul(d, s, rnd),
+            MaskOp::Subtract => d - d.min(s),
+            MaskOp::Xor => d.max(s) - d.min(s),
+        }
+    }
+}
+
+/// Combine mask pixels with another slice.
+///
+/// * `dst` Destination mask pixels.
+/// * `src` Source pixels (alpha channel is used).
+/// * `op` Mask operation.
+/// * `rnd` Rounding policy.
+pub fn mask_slice<A, B, C, H>(
+    dst: &mut [Mask<A>],
+    src: &[B],
+    op: MaskOp,
+    rnd: Rounding,
+) where
+    C: Channel + From<H>,
+    A: Alpha<Chan = C> + From<C>,
+    B: Format<Chan = H>,
+    H: Channel + From<C>,
+{
+    for (bot, top) in dst.iter_mut().zip(src) {
+        let src: Mask<A> = top.convert();
+        let a = op.combine(bot.alpha().value(), src.alpha().value(), rnd);
+        *bot = Mask::new(a);
+    }
+}
+
+/// Invert mask pixels.
+///
+/// * `dst` Mask pixels.
+pub fn invert_slice<A, C>(dst: &mut [Mask<A>])
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+{
+    for m in dst.iter_mut() {
+        *m = Mask::new(C::MAX - m.alpha().value());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pix::{Mask16, Mask8};
+
+    #[test]
+    fn mask_ops() {
+        let m = |v: &[u8]| -> Vec<Mask8> {
+            v.iter().map(|v| Mask8::new(*v)).collect()
+        };
+        let s = m(&[0x00, 0x40, 0xFF, 0x80]);
+        let ops = [
+            (MaskOp::Union, [0x80, 0x40, 0xFF, 0xFF]),
+            (MaskOp::Intersection, [0x00, 0x00, 0xFF, 0x80]),
+            (MaskOp::Multiply, [0x00, 0x00, 0xFF, 0x80]),
+            (MaskOp::Subtract, [0x80, 0x00, 0x00, 0x7F]),
+            (MaskOp::Xor, [0x80, 0x40, 0x00, 0x7F]),
+        ];
+        for (op, v) in ops.iter() {
+            let mut d = m(&[0x80, 0x00, 0xFF, 0xFF]);
+            mask_slice(&mut d, &s, *op, Rounding::Fast);
+            assert_eq!(d, m(v), "{:?}", op);
+        }
+        let mut d = m(&[0x00, 0x40, 0xFF]);
+        invert_slice(&mut d);
+        assert_eq!(d, m(&[0xFF, 0xBF, 0x00]));
+        let mut d = m(&[0x80]);
+        let s = [Mask16::new(0xFFFF)];
+        mask_slice(&mut d, &s, MaskOp::Union, Rounding::Fast);
+        assert_eq!(d, m(&[0xFF]));
     }
 }
@@ -0,0 +1,242 @@
+// morph.rs     Morphological operations on masks.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use pix::{Alpha, Channel, Mask, Raster, RasterBuilder};
+
+/// Structuring element for morphological operations.
+///
+/// Each variant contains the radius, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Structure {
+    /// Square with sides of `radius * 2 + 1`
+    Square(u32),
+    /// Diamond, with corners `radius` from the center
+    Diamond(u32),
+    /// Disc, containing pixels within `radius` of the center
+    Disc(u32),
+}
+
+impl Structure {
+    /// Get the radius
+    fn radius(self) -> usize {
+        let r = match self {
+            Structure::Square(r) => r,
+            Structure::Diamond(r) => r,
+            Structure::Disc(r) => r,
+        };
+        r as usize
+    }
+
+    /// Get the half-width of one row
+    ///
+    /// * `dy` Row offset from the center.
+    fn half_width(self, dy: usize) -> usize {
+        let r = self.radius();
+        match self {
+            Structure::Square(_) => r,
+            Structure::Diamond(_) => r - dy,
+            Structure::Disc(_) => ((r * r - dy * dy) as f64).sqrt() as usize,
+        }
+    }
+}
+
+/// Dilate a mask, growing opaque areas.
+///
+/// Each pixel becomes the maximum of pixels within the structuring element.
+/// Pixels beyond the edges are ignored.
+///
+/// * `mask` Source mask.
+/// * `elem` Structuring element.
+pub fn dilate<C, A>(
+    mask: &Raster<Mask<A>>,
+    elem: Structure,
+) -> Raster<Mask<A>>
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+{
+    morph(mask, elem, C::max)
+}
+
+/// Erode a mask, shrinking opaque areas.
+///
+/// Each pixel becomes the minimum of pixels within the structuring element.
+/// Pixels beyond the edges are ignored.
+///
+/// * `mask` Source mask.
+/// * `elem` Structuring element.
+pub fn erode<C, A>(
+    mask: &Raster<Mask<A>>,
+    elem: Structure,
+) -> Raster<Mask<A>>
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+{
+    morph(mask, elem, C::min)
+}
+
+/// Open a mask (erode, then dilate), removing small opaque areas.
+///
+/// * `mask` Source mask.
+/// * `elem` Structuring element.
+pub fn open<C, A>(
+    mask: &Raster<Mask<A>>,
+    elem: Structure,
+) -> Raster<Mask<A>>
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+{
+    dilate(&erode(mask, elem), elem)
+}
+
+/// Close a mask (dilate, then erode), filling small transparent areas.
+///
+/// * `mask` Source mask.
+/// * `elem` Structuring element.
+pub fn close<C, A>(
+    mask: &Raster<Mask<A>>,
+    elem: Structure,
+) -> Raster<Mask<A>>
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+{
+    erode(&dilate(mask, elem), elem)
+}
+
+/// Apply a morphological operation.
+///
+/// Rows of the structuring element are handled by widening the source one
+/// pixel at a time, so each operation is done `radius` times per pixel for
+/// widening, plus once for each row.
+///
+/// * `mask` Source mask.
+/// * `elem` Structuring element.
+/// * `op` Operation to combine two values (`min` or `max`).
+fn morph<C, A, O>(
+    mask: &Raster<Mask<A>>,
+    elem: Structure,
+    op: O,
+) -> Raster<Mask<A>>
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+    O: Fn(C, C) -> C,
+{
+    let w = mask.width() as usize;
+    let h = mask.height() as usize;
+    let r = elem.radius();
+    let mut level: Vec<C> =
+        mask.as_slice().iter().map(|p| p.alpha().value()).collect();
+    let mut out = level.clone();
+    if w > 0 && h > 0 {
+        for k in 0..=r {
+            if k > 0 {
+                level = widen(&level, w, &op);
+            }
+            let rows = 0..=r.min(h - 1);
+            for dy in rows.filter(|dy| elem.half_width(*dy) == k) {
+                combine_rows(&mut out, &level, w, dy, &op);
+            }
+        }
+    }
+    let pixels: Vec<Mask<A>> = out.into_iter().map(Mask::new).collect();
+    RasterBuilder::new().with_pixels(mask.width(), mask.height(), pixels)
+}
+
+/// Combine rows of values with rows above and below.
+///
+/// * `out` Output values.
+/// * `values` Values to combine.
+/// * `w` Width of rows.
+/// * `dy` Row offset.
+/// * `op` Operation to combine two values.
+fn combine_rows<C, O>(out: &mut [C], values: &[C], w: usize, dy: usize, op: O)
+where
+    C: Channel,
+    O: Fn(C, C) -> C,
+{
+    let h = out.len() / w;
+    for (y, row) in out.chunks_mut(w).enumerate() {
+        let above = y.checked_sub(dy);
+        let below = Some(y + dy).filter(|sy| *sy < h);
+        for sy in above.iter().chain(below.iter()) {
+            let src = &values[sy * w..(sy + 1) * w];
+            for (d, s) in row.iter_mut().zip(src) {
+                *d = op(*d, *s);
+            }
+        }
+    }
+}
+
+/// Widen each row of values by one pixel on each side.
+fn widen<C, O>(values: &[C], w: usize, op: O) -> Vec<C>
+where
+    C: Channel,
+    O: Fn(C, C) -> C,
+{
+    let mut out = Vec::with_capacity(values.len());
+    for row in values.chunks(w) {
+        for x in 0..w {
+            let mut v = row[x];
+            if x > 0 {
+                v = op(v, row[x - 1]);
+            }
+            if x + 1 < w {
+                v = op(v, row[x + 1]);
+            }
+            out.push(v);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pix::*;
+
+    fn dot(size: u32) -> Raster<Mask8> {
+        let mut r = RasterBuilder::<Mask8>::new().with_clear(size, size);
+        r.set_pixel(size / 2, size / 2, Mask8::new(0xFF));
+        r
+    }
+
+    #[test]
+    fn shapes() {
+        let r = dilate(&dot(5), Structure::Diamond(1));
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xFF, 0x00, 0x00,
+            0x00, 0xFF, 0xFF, 0xFF, 0x00,
+            0x00, 0x00, 0xFF, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+        let count = |r: &Raster<Mask8>| {
+            r.as_u8_slice().iter().filter(|v| **v == 0xFF).count()
+        };
+        assert_eq!(count(&dilate(&dot(7), Structure::Square(2))), 25);
+        assert_eq!(count(&dilate(&dot(7), Structure::Diamond(2))), 13);
+        assert_eq!(count(&dilate(&dot(7), Structure::Disc(2))), 13);
+        assert_eq!(count(&dilate(&dot(9), Structure::Disc(3))), 29);
+    }
+
+    #[test]
+    fn erode_open_close() {
+        let r = dilate(&dot(5), Structure::Square(1));
+        let e = erode(&r, Structure::Square(1));
+        assert_eq!(e.as_u8_slice(), dot(5).as_u8_slice());
+        let o = open(&dot(5), Structure::Disc(1));
+        assert_eq!(o.as_u8_slice(), &[0; 25][..]);
+        let mut r = RasterBuilder::<Mask8>::new()
+            .with_color(5, 5, Mask8::new(0x80));
+        r.set_pixel(2, 2, Mask8::new(0x00));
+        let c = close(&r, Structure::Square(1));
+        assert_eq!(c.as_u8_slice(), &[0x80; 25][..]);
+    }
+}
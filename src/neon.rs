@@ -0,0 +1,412 @@
+// neon.rs      SIMD compositing for 8-bit pixels (NEON).
+//
+// Copyright (c) 2018-2019  Douglas P Lau
+//
+use crate::blend::{bits, scale, separate_mask, Rounding};
+use pix::{AlphaMode, AlphaModeID, Ch8, Format, GammaMode, GammaModeID};
+use std::arch::aarch64::*;
+use std::arch::is_aarch64_feature_detected;
+use std::mem::size_of;
+use std::sync::OnceLock;
+
+/// Check if source format is an 8-bit alpha mask.
+///
+/// The only format with unknown alpha and gamma modes is `Mask`.
+fn is_mask8<B: Format>() -> bool {
+    <B as AlphaMode>::ID == AlphaModeID::UnknownAlpha
+        && <B as GammaMode>::ID == GammaModeID::UnknownGamma
+        && size_of::<B>() == 1
+}
+
+/// Check if format is associated 8-bit RGBA.
+///
+/// `F` must be an `Rgb` format -- with 8-bit channels and 4 bytes, it can
+/// only be `Rgb<Ch8, Translucent<Ch8>, AssociatedAlpha, G>`.
+fn is_rgba8<F: Format>() -> bool {
+    <F as AlphaMode>::ID == AlphaModeID::Associated
+        && size_of::<F>() == 4
+        && size_of::<F::Chan>() == 1
+}
+
+/// Check if format is associated 8-bit Gray+alpha.
+///
+/// `F` must be a `Gray` format -- with 8-bit channels and 2 bytes, it can
+/// only be `Gray<Ch8, Translucent<Ch8>, AssociatedAlpha, G>`.
+fn is_gray_alpha8<F: Format>() -> bool {
+    <F as AlphaMode>::ID == AlphaModeID::Associated
+        && size_of::<F>() == 2
+        && size_of::<F::Chan>() == 1
+}
+
+/// Get the mask color constants for the RGBA kernel.
+///
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// Returns color channels, and alpha scaled by opacity.  With exact
+/// rounding, the color is associated and scaled by opacity, so it can be
+/// multiplied directly with the mask.  Otherwise, it is separated from alpha.
+fn mask_color<F: Format>(
+    clr: F,
+    opacity: Ch8,
+    rnd: Rounding,
+) -> ([u8; 3], u8) {
+    debug_assert_eq!(size_of::<F>(), 4);
+    let rgba: [u8; 4] = bits(clr);
+    let a = Ch8::new(rgba[3]);
+    let k = |c| {
+        let c = if rnd == Rounding::Exact {
+            scale(Ch8::new(c), opacity, rnd)
+        } else {
+            separate_mask(Ch8::new(c), a, rnd)
+        };
+        u8::from(c)
+    };
+    let k = [k(rgba[0]), k(rgba[1]), k(rgba[2])];
+    (k, u8::from(scale(a, opacity, rnd)))
+}
+
+/// Make a lookup table of mask values converted to gray values.
+///
+/// Converting a mask to a gray format encodes gamma, so the table depends
+/// on the gamma mode of `F`.
+fn make_mask_lut<F, B>() -> [u8; 256]
+where
+    F: Format,
+    B: Format,
+    F::Chan: From<B::Chan>,
+{
+    debug_assert_eq!(size_of::<B>(), 1);
+    let mut lut = [0; 256];
+    for (m, v) in lut.iter_mut().enumerate() {
+        let mask: B = unsafe { std::mem::transmute_copy(&(m as u8)) };
+        let gray: F = mask.convert();
+        let gray: [u8; 2] = unsafe { std::mem::transmute_copy(&gray) };
+        *v = gray[0];
+    }
+    lut
+}
+
+/// Get the cached lookup table of mask values converted to gray values.
+///
+/// Tables are built once for each gamma mode; `None` is returned for
+/// gamma modes without a cached table.
+fn mask_lut<F, B>() -> Option<&'static [u8; 256]>
+where
+    F: Format,
+    B: Format,
+    F::Chan: From<B::Chan>,
+{
+    static LINEAR: OnceLock<[u8; 256]> = OnceLock::new();
+    static SRGB: OnceLock<[u8; 256]> = OnceLock::new();
+    let lut = match <F as GammaMode>::ID {
+        GammaModeID::Linear => &LINEAR,
+        GammaModeID::Srgb => &SRGB,
+        _ => return None,
+    };
+    Some(lut.get_or_init(make_mask_lut::<F, B>))
+}
+
+/// Blend associated 8-bit RGBA or Gray+alpha pixels with an 8-bit alpha
+/// mask, using SIMD.
+///
+/// Other formats are ignored.  Any pixels remaining at the end of the slices
+/// must be blended by the caller.
+///
+/// * `dst` Destination pixels (must be an `Rgb` or `Gray` format).
+/// * `src` Source mask pixels.
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// Returns the number of pixels blended.
+pub(crate) fn over_slice<F, B>(
+    dst: &mut [F],
+    src: &[B],
+    clr: F,
+    opacity: B::Chan,
+    rnd: Rounding,
+) -> usize
+where
+    F: Format,
+    B: Format,
+    F::Chan: From<B::Chan>,
+{
+    if !is_mask8::<B>() || !is_aarch64_feature_detected!("neon") {
+        return 0;
+    }
+    let len = dst.len().min(src.len());
+    let opacity: Ch8 = unsafe { std::mem::transmute_copy(&opacity) };
+    let exact = rnd == Rounding::Exact;
+    let pix = dst.as_mut_ptr() as *mut u8;
+    let mask = src.as_ptr() as *const u8;
+    if is_rgba8::<F>() {
+        let (k, a) = mask_color(clr, opacity, rnd);
+        unsafe { over_rgba_neon(pix, mask, len, k, a, exact) }
+    } else if is_gray_alpha8::<F>() && len >= 16 {
+        let lut = match mask_lut::<F, B>() {
+            Some(lut) => lut,
+            None => return 0,
+        };
+        let [v, a]: [Ch8; 2] = unsafe { std::mem::transmute_copy(&clr) };
+        let v = u8::from(scale(v, opacity, rnd));
+        let a = u8::from(scale(a, opacity, rnd));
+        unsafe { over_gray_neon(pix, mask, len, lut, (v, a), exact) }
+    } else {
+        0
+    }
+}
+
+/// Composite a color with a mask onto RGBA pixels (NEON).
+///
+/// * `k` Mask color, separated from alpha.  With `exact`, this is the
+///   associated color instead.
+/// * `a` Mask color alpha.
+/// * `exact` Round products to nearest.
+///
+/// Returns the number of pixels blended (a multiple of 16).
+#[target_feature(enable = "neon")]
+unsafe fn over_rgba_neon(
+    pix: *mut u8,
+    mask: *const u8,
+    len: usize,
+    k: [u8; 3],
+    a: u8,
+    exact: bool,
+) -> usize {
+    let kr = vdupq_n_u8(k[0]);
+    let kg = vdupq_n_u8(k[1]);
+    let kb = vdupq_n_u8(k[2]);
+    let ca = vdupq_n_u8(a);
+    // Truncate len to multiple of 16
+    let len16 = (len >> 4) << 4;
+    // 16 pixels at a time
+    for i in (0..len16).step_by(16) {
+        let dst = pix.add(i * 4);
+        // get 16 alpha values from src
+        let m = vld1q_u8(mask.add(i));
+        // get RGBA values from dst, deinterleaved into channels
+        let mut bot = vld4q_u8(dst);
+        // Source alpha: mask alpha * mask value
+        let sa = mul_u8x16_neon(ca, m, exact);
+        let inv = vmvnq_u8(sa);
+        // Source: separated color * source alpha, or associated color *
+        // mask value when exact
+        let f = if exact { m } else { sa };
+        let sr = mul_u8x16_neon(kr, f, exact);
+        let sg = mul_u8x16_neon(kg, f, exact);
+        let sb = mul_u8x16_neon(kb, f, exact);
+        // Over: s + b * (1 - sa)
+        bot.0 = over_u8x16_neon(sr, inv, bot.0, exact);
+        bot.1 = over_u8x16_neon(sg, inv, bot.1, exact);
+        bot.2 = over_u8x16_neon(sb, inv, bot.2, exact);
+        bot.3 = over_u8x16_neon(sa, inv, bot.3, exact);
+        // store blended pixels
+        vst4q_u8(dst, bot);
+    }
+    len16
+}
+
+/// Composite a color with a mask onto Gray+alpha pixels (NEON).
+///
+/// * `lut` Mask values converted to gray values.
+/// * `clr` Mask color value (associated) and alpha.
+/// * `exact` Round products to nearest.
+///
+/// Returns the number of pixels blended (a multiple of 16).
+#[target_feature(enable = "neon")]
+unsafe fn over_gray_neon(
+    pix: *mut u8,
+    mask: *const u8,
+    len: usize,
+    lut: &[u8; 256],
+    clr: (u8, u8),
+    exact: bool,
+) -> usize {
+    let (v, a) = clr;
+    let tbl = vld1q_u8_x4(lut.as_ptr());
+    let tbl = [
+        tbl,
+        vld1q_u8_x4(lut.as_ptr().add(64)),
+        vld1q_u8_x4(lut.as_ptr().add(128)),
+        vld1q_u8_x4(lut.as_ptr().add(192)),
+    ];
+    let cv = vdupq_n_u8(v);
+    let ca = vdupq_n_u8(a);
+    let step = vdupq_n_u8(64);
+    // Truncate len to multiple of 16
+    let len16 = (len >> 4) << 4;
+    // 16 pixels at a time
+    for i in (0..len16).step_by(16) {
+        let dst = pix.add(i * 2);
+        // get 16 alpha values from src
+        let m = vld1q_u8(mask.add(i));
+        // look up gray values of mask (out of range indices give 0)
+        let i1 = vsubq_u8(m, step);
+        let i2 = vsubq_u8(i1, step);
+        let i3 = vsubq_u8(i2, step);
+        let g = vorrq_u8(
+            vorrq_u8(vqtbl4q_u8(tbl[0], m), vqtbl4q_u8(tbl[1], i1)),
+            vorrq_u8(vqtbl4q_u8(tbl[2], i2), vqtbl4q_u8(tbl[3], i3)),
+        );
+        // get values from dst, deinterleaved into value and alpha
+        let mut bot = vld2q_u8(dst);
+        // Source: mask color * mask gray
+        let sv = mul_u8x16_neon(cv, g, exact);
+        let sa = mul_u8x16_neon(ca, m, exact);
+        let inv = vmvnq_u8(sa);
+        bot.0 = over_u8x16_neon(sv, inv, bot.0, exact);
+        bot.1 = over_u8x16_neon(sa, inv, bot.1, exact);
+        // store blended pixels
+        vst2q_u8(dst, bot);
+    }
+    len16
+}
+
+/// Composite one channel of top over bottom pixels (NEON).
+///
+/// * `t` Top channel values.
+/// * `one_minus_sa` Inverse of top alpha.
+/// * `b` Bottom channel values.
+/// * `exact` Round products to nearest.
+#[target_feature(enable = "neon")]
+unsafe fn over_u8x16_neon(
+    t: uint8x16_t,
+    one_minus_sa: uint8x16_t,
+    b: uint8x16_t,
+    exact: bool,
+) -> uint8x16_t {
+    vqaddq_u8(t, mul_u8x16_neon(b, one_minus_sa, exact))
+}
+
+/// Multiply packed u8 values as `Ch8` channels (NEON).
+#[target_feature(enable = "neon")]
+unsafe fn mul_u8x16_neon(
+    a: uint8x16_t,
+    b: uint8x16_t,
+    exact: bool,
+) -> uint8x16_t {
+    let al = vmovl_u8(vget_low_u8(a));
+    let bl = vmovl_u8(vget_low_u8(b));
+    let ah = vmovl_high_u8(a);
+    let bh = vmovl_high_u8(b);
+    let (lo, hi) = if exact {
+        (mul_exact_u16x8_neon(al, bl), mul_exact_u16x8_neon(ah, bh))
+    } else {
+        (mul_u16x8_neon(al, bl), mul_u16x8_neon(ah, bh))
+    };
+    vmovn_high_u16(vmovn_u16(lo), hi)
+}
+
+/// Multiply u8 values in u16 lanes as `Ch8` channels (NEON).
+#[target_feature(enable = "neon")]
+unsafe fn mul_u16x8_neon(a: uint16x8_t, b: uint16x8_t) -> uint16x8_t {
+    // Same as Ch8 multiply: expand each value to 12 bits (v << 4 | v >> 4),
+    // then keep the high 16 bits of the 24-bit product.
+    let a = vorrq_u16(vshlq_n_u16(a, 4), vshrq_n_u16(a, 4));
+    let b = vorrq_u16(vshlq_n_u16(b, 4), vshrq_n_u16(b, 4));
+    let lo = vmull_u16(vget_low_u16(a), vget_low_u16(b));
+    let hi = vmull_high_u16(a, b);
+    vshrn_high_n_u32(vshrn_n_u32(lo, 16), hi, 16)
+}
+
+/// Multiply u8 values in u16 lanes, rounding to nearest (NEON).
+#[target_feature(enable = "neon")]
+unsafe fn mul_exact_u16x8_neon(a: uint16x8_t, b: uint16x8_t) -> uint16x8_t {
+    // c = a * b + 128, then (c + (c >> 8)) >> 8
+    let c = vaddq_u16(vmulq_u16(a, b), vdupq_n_u16(128));
+    vshrq_n_u16(vaddq_u16(c, vshrq_n_u16(c, 8)), 8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixture::{gray8, mask8, rgba8};
+    use crate::Blend;
+    use pix::*;
+
+    /// Check that pixels are within 1 of the fallback version.
+    fn assert_within_one<F: Format<Chan = Ch8>>(pix: &[F], fallback: &[F]) {
+        for (p, f) in pix.iter().zip(fallback) {
+            for (c, d) in p.rgba().iter().zip(f.rgba().iter()) {
+                let c = i32::from(u8::from(*c));
+                let d = i32::from(u8::from(*d));
+                assert!((c - d).abs() <= 1, "{:?} != {:?}", p, f);
+            }
+        }
+    }
+
+    #[test]
+    fn rgba_matches_fallback() {
+        let colors = [
+            AssocSRgba8::new(0xFF, 0x80, 0x40),
+            AssocSRgba8::with_alpha(0x20, 0x40, 0x60, 0x80),
+            AssocSRgba8::with_alpha(0x01, 0x02, 0x03, 0x04),
+            AssocSRgba8::with_alpha(0x00, 0x00, 0x00, 0x00),
+        ];
+        let rnd = Rounding::Fast;
+        for len in 0..300 {
+            for clr in colors.iter() {
+                for o in [0xFF, 0xB3, 0x2C].iter().map(|o| Ch8::new(*o)) {
+                    let (mut dst, mask) = (rgba8(len), mask8(len));
+                    let mut fb = dst.clone();
+                    AssocSRgba8::over_slice(&mut dst, &mask, *clr, o, rnd);
+                    AssocSRgba8::over_fallback(&mut fb, &mask, *clr, o, rnd);
+                    assert_within_one(&dst, &fb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gray_matches_fallback() {
+        let colors = [
+            AssocSGrayAlpha8::new(0xFF),
+            AssocSGrayAlpha8::with_alpha(0x40, 0x80),
+            AssocSGrayAlpha8::with_alpha(0x01, 0x04),
+            AssocSGrayAlpha8::with_alpha(0x00, 0x00),
+        ];
+        let rnd = Rounding::Fast;
+        for len in 0..300 {
+            for clr in colors.iter().copied() {
+                for o in [0xFF, 0xB3, 0x2C].iter().map(|o| Ch8::new(*o)) {
+                    let (mut dst, m) = (gray8(len), mask8(len));
+                    let mut fb = dst.clone();
+                    AssocSGrayAlpha8::over_slice(&mut dst, &m, clr, o, rnd);
+                    AssocSGrayAlpha8::over_fallback(&mut fb, &m, clr, o, rnd);
+                    assert_within_one(&dst, &fb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn kernels() {
+        let m = mask8(256);
+        let mp = m.as_ptr() as *const u8;
+        let rnd = Rounding::Fast;
+        for o in [0xFF, 0xB3, 0x2C].iter().map(|o| Ch8::new(*o)) {
+            let clr = AssocSRgba8::with_alpha(0x40, 0x80, 0x60, 0xC0);
+            let mut pix = rgba8(256);
+            let mut fallback = pix.clone();
+            AssocSRgba8::over_fallback(&mut fallback, &m, clr, o, rnd);
+            let (k, a) = mask_color(clr, o, rnd);
+            let p = pix.as_mut_ptr() as *mut u8;
+            let n = unsafe { over_rgba_neon(p, mp, 256, k, a, false) };
+            assert_eq!(n, 256);
+            assert_within_one(&pix, &fallback);
+            let clr = AssocSGrayAlpha8::with_alpha(0x60, 0xC0);
+            let mut pix = gray8(256);
+            let mut fallback = pix.clone();
+            AssocSGrayAlpha8::over_fallback(&mut fallback, &m, clr, o, rnd);
+            let lut = mask_lut::<AssocSGrayAlpha8, Mask8>().unwrap();
+            let p = pix.as_mut_ptr() as *mut u8;
+            let s = |c| u8::from(scale(Ch8::new(c), o, rnd));
+            let clr = (s(0x60), s(0xC0));
+            let n = unsafe { over_gray_neon(p, mp, 256, lut, clr, false) };
+            assert_eq!(n, 256);
+            assert_within_one(&pix, &fallback);
+        }
+    }
+}
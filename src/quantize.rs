@@ -0,0 +1,426 @@
+// quantize.rs  Color quantization to a palette.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use crate::dither::{Dither, Quantizer};
+use pix::{Ch8, Format, Palette, Raster, RasterBuilder, SepLGray8, SepSRgba8};
+use std::collections::HashMap;
+
+/// Maximum number of palette entries
+const MAX_COLORS: usize = 256;
+
+/// Maximum number of k-means iterations
+const KMEANS_ITERATIONS: usize = 8;
+
+/// Method for choosing palette colors in [quantize](fn.quantize.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantize {
+    /// Split boxes of colors at the median of their widest channel
+    MedianCut,
+    /// Merge the least common branches of an octree of colors
+    Octree,
+    /// Median cut, refined with k-means clustering
+    KMeans,
+}
+
+/// Color channels, with number of pixels
+type Color = ([u8; 4], usize);
+
+/// Node of an octree (with 16 children, for alpha)
+#[derive(Default)]
+struct Node {
+    /// Sum of channels for all pixels
+    sum: [u64; 4],
+    /// Number of pixels
+    count: usize,
+    /// Child node indices (zero for none)
+    children: [usize; 16],
+}
+
+impl Node {
+    /// Add pixels of one color
+    fn add(&mut self, clr: &Color) {
+        for (s, c) in self.sum.iter_mut().zip(&clr.0) {
+            *s += u64::from(*c) * clr.1 as u64;
+        }
+        self.count += clr.1;
+    }
+
+    /// Check if the node is a leaf
+    fn is_leaf(&self) -> bool {
+        self.children.iter().all(|c| *c == 0)
+    }
+
+    /// Get the mean color of all pixels
+    fn mean(&self) -> [u8; 4] {
+        let n = self.count.max(1) as u64;
+        self.sum.map(|s| ((s + n / 2) / n) as u8)
+    }
+}
+
+/// Quantize a raster to a palette of colors.
+///
+/// If the raster contains no more than `max_colors` colors, they are all
+/// used exactly.
+///
+/// * `raster` Source raster.
+/// * `max_colors` Maximum number of palette colors (1 to 256).
+/// * `method` Method for choosing colors.
+/// * `dither` Dithering method for remapping pixels, or `None`.
+///
+/// Returns the palette, and a raster of palette indices.
+///
+/// ```
+/// # use pix::*;
+/// # use pixops::*;
+/// let r = RasterBuilder::<SepSRgba8>::new().with_clear(4, 4);
+/// let (p, r) = quantize(&r, 16, Quantize::MedianCut, None);
+/// assert_eq!(p.len(), 1);
+/// assert_eq!(r.as_u8_slice(), &[0; 16][..]);
+/// ```
+pub fn quantize(
+    raster: &Raster<SepSRgba8>,
+    max_colors: usize,
+    method: Quantize,
+    dither: Option<Dither>,
+) -> (Palette<SepSRgba8>, Raster<SepLGray8>) {
+    let max = max_colors.clamp(1, MAX_COLORS);
+    let colors = histogram(raster);
+    let entries = if colors.len() <= max {
+        colors.iter().map(|c| c.0).collect()
+    } else {
+        match method {
+            Quantize::MedianCut => median_cut(colors, max),
+            Quantize::Octree => octree(&colors, max),
+            Quantize::KMeans => {
+                let centers = median_cut(colors.clone(), max);
+                kmeans(&colors, centers)
+            }
+        }
+    };
+    let mut palette = Palette::new(entries.len());
+    for clr in entries {
+        palette.set_entry(SepSRgba8::with_rgba(clr.map(Ch8::new)));
+    }
+    let indexed = remap(raster, &palette, dither);
+    (palette, indexed)
+}
+
+/// Remap a raster to the nearest colors of a palette.
+///
+/// * `raster` Source raster.
+/// * `palette` Palette of colors (up to 256).
+/// * `dither` Dithering method, or `None`.
+///
+/// Returns a raster of palette indices.
+///
+/// # Panics
+///
+/// Panics if the palette has more than 256 entries, or if it is empty and
+/// the raster is not.
+pub fn remap(
+    raster: &Raster<SepSRgba8>,
+    palette: &Palette<SepSRgba8>,
+    dither: Option<Dither>,
+) -> Raster<SepLGray8> {
+    let entries: Vec<[u8; 4]> = (0..palette.len())
+        .filter_map(|i| palette.entry(i))
+        .map(rgba)
+        .collect();
+    assert!(entries.len() <= MAX_COLORS, "too many palette entries");
+    let (w, h) = (raster.width(), raster.height());
+    let mut dst = RasterBuilder::<SepLGray8>::new().with_clear(w, h);
+    if raster.as_slice().is_empty() {
+        return dst;
+    }
+    assert!(!entries.is_empty(), "palette is empty");
+    let index = |v: [f32; 4]| {
+        let i = nearest(&entries, v);
+        (SepLGray8::new(i as u8), entries[i].map(f32::from))
+    };
+    match dither {
+        None => {
+            let mut cache = HashMap::new();
+            for (d, p) in dst.as_slice_mut().iter_mut().zip(raster.as_slice())
+            {
+                let clr = rgba(*p);
+                *d = *cache
+                    .entry(clr)
+                    .or_insert_with(|| index(clr.map(f32::from)).0);
+            }
+        }
+        Some(method) => {
+            let w = w as usize;
+            // Estimate distance between colors, as if evenly spaced
+            let levels = (entries.len() as f32).cbrt().round().max(2.0);
+            let spread = 255.0 / (levels - 1.0);
+            let mut q = Quantizer::new(method, w, spread);
+            let rows = dst.as_slice_mut().chunks_mut(w);
+            for (y, (d, s)) in rows.zip(raster.as_slice().chunks(w)).enumerate()
+            {
+                let v: Vec<_> =
+                    s.iter().map(|p| rgba(*p).map(f32::from)).collect();
+                q.row(d, &v, 0, y, index);
+            }
+        }
+    }
+    dst
+}
+
+/// Get the channels of a pixel
+fn rgba(p: SepSRgba8) -> [u8; 4] {
+    p.rgba().map(u8::from)
+}
+
+/// Find the index of the nearest color in a palette
+fn nearest(entries: &[[u8; 4]], v: [f32; 4]) -> usize {
+    let dist = |clr: &[u8; 4]| -> f32 {
+        clr.iter()
+            .zip(&v)
+            .map(|(c, v)| (f32::from(*c) - v) * (f32::from(*c) - v))
+            .sum()
+    };
+    let mut best = (0, f32::MAX);
+    for (i, clr) in entries.iter().enumerate() {
+        let d = dist(clr);
+        if d < best.1 {
+            best = (i, d);
+        }
+    }
+    best.0
+}
+
+/// Count pixels of each color in a raster, sorted by color.
+fn histogram(raster: &Raster<SepSRgba8>) -> Vec<Color> {
+    let mut counts = HashMap::new();
+    for p in raster.as_slice() {
+        *counts.entry(rgba(*p)).or_insert(0) += 1;
+    }
+    let mut colors: Vec<Color> = counts.into_iter().collect();
+    colors.sort_unstable();
+    colors
+}
+
+/// Get the mean of colors, weighted by number of pixels
+fn mean(colors: &[Color]) -> [u8; 4] {
+    let mut node = Node::default();
+    colors.iter().for_each(|c| node.add(c));
+    node.mean()
+}
+
+/// Get the widest channel of colors, and its range
+fn widest(colors: &[Color]) -> (usize, u8) {
+    let mut lo = [u8::MAX; 4];
+    let mut hi = [u8::MIN; 4];
+    for (clr, _) in colors {
+        for c in 0..4 {
+            lo[c] = lo[c].min(clr[c]);
+            hi[c] = hi[c].max(clr[c]);
+        }
+    }
+    (0..4)
+        .map(|c| (c, hi[c].saturating_sub(lo[c])))
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+/// Choose colors by median cut.
+///
+/// The box of colors with the widest channel range is split at the median
+/// pixel, until there are enough boxes.
+///
+/// * `colors` Colors to choose from.
+/// * `max` Number of colors to choose.
+fn median_cut(colors: Vec<Color>, max: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![colors];
+    while boxes.len() < max {
+        let (i, (c, range)) = boxes
+            .iter()
+            .map(|b| widest(b))
+            .enumerate()
+            .max_by_key(|(_, (_, range))| *range)
+            .unwrap_or((0, (0, 0)));
+        if range == 0 {
+            break;
+        }
+        let mut b = boxes.swap_remove(i);
+        b.sort_unstable_by_key(|clr| clr.0[c]);
+        let total: usize = b.iter().map(|clr| clr.1).sum();
+        let mut count = 0;
+        let mut split = b.len() - 1;
+        for (j, clr) in b.iter().enumerate() {
+            count += clr.1;
+            if count * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        let split = split.clamp(1, b.len() - 1);
+        let hi = b.split_off(split);
+        boxes.push(b);
+        boxes.push(hi);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+/// Choose colors with an octree.
+///
+/// Each color is a leaf of the tree, one level for each bit.  Starting at
+/// the deepest level, the least common branches are merged into leaves,
+/// until there are few enough.
+///
+/// * `colors` Colors to choose from.
+/// * `max` Number of colors to choose.
+fn octree(colors: &[Color], max: usize) -> Vec<[u8; 4]> {
+    let mut nodes = vec![Node::default()];
+    let mut branches: Vec<Vec<usize>> = vec![Vec::new(); 8];
+    for clr in colors {
+        let mut n = 0;
+        nodes[n].add(clr);
+        for (depth, level) in branches.iter_mut().enumerate() {
+            let bit = 7 - depth;
+            let i = clr.0.iter().enumerate().fold(0, |i, (k, c)| {
+                i | (usize::from(*c >> bit) & 1) << k
+            });
+            if nodes[n].children[i] == 0 {
+                if nodes[n].is_leaf() {
+                    level.push(n);
+                }
+                nodes.push(Node::default());
+                nodes[n].children[i] = nodes.len() - 1;
+            }
+            n = nodes[n].children[i];
+            nodes[n].add(clr);
+        }
+    }
+    let mut leaves = colors.len();
+    'merge: for level in branches.iter_mut().rev() {
+        level.sort_by_key(|n| nodes[*n].count);
+        for n in level.iter() {
+            if leaves <= max {
+                break 'merge;
+            }
+            let children = nodes[*n].children.iter().filter(|c| **c > 0);
+            leaves -= children.count() - 1;
+            nodes[*n].children = [0; 16];
+        }
+    }
+    let mut entries = Vec::with_capacity(leaves);
+    let mut stack = vec![0];
+    while let Some(n) = stack.pop() {
+        let node = &nodes[n];
+        if node.is_leaf() {
+            entries.push(node.mean());
+        } else {
+            stack.extend(node.children.iter().rev().filter(|c| **c > 0));
+        }
+    }
+    entries
+}
+
+/// Refine colors with k-means clustering.
+///
+/// Each color is assigned to the nearest center, and centers are moved to
+/// the mean of their colors, until they stop moving.
+///
+/// * `colors` Colors to cluster.
+/// * `centers` Initial cluster centers.
+fn kmeans(colors: &[Color], mut centers: Vec<[u8; 4]>) -> Vec<[u8; 4]> {
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut clusters: Vec<Node> =
+            centers.iter().map(|_| Node::default()).collect();
+        for clr in colors {
+            let i = nearest(&centers, clr.0.map(f32::from));
+            clusters[i].add(clr);
+        }
+        let next: Vec<[u8; 4]> = clusters
+            .iter()
+            .zip(&centers)
+            .map(|(n, c)| if n.count > 0 { n.mean() } else { *c })
+            .collect();
+        if next == centers {
+            break;
+        }
+        centers = next;
+    }
+    centers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Make a raster with a gradient of colors
+    fn gradient() -> Raster<SepSRgba8> {
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(16, 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                let (r8, g8) = (x as u8 * 16, y as u8 * 16);
+                r.set_pixel(x, y, SepSRgba8::new(r8, g8, 0x80));
+            }
+        }
+        r
+    }
+
+    /// Get the total squared error of a quantized raster
+    fn error(
+        r: &Raster<SepSRgba8>,
+        p: &Palette<SepSRgba8>,
+        i: &Raster<SepLGray8>,
+    ) -> u32 {
+        let pixels = r.as_slice().iter().zip(i.as_u8_slice());
+        pixels
+            .map(|(c, i)| {
+                let e = rgba(p.entry(usize::from(*i)).unwrap());
+                let c = rgba(*c);
+                let d = c.iter().zip(&e).map(|(c, e)| c.abs_diff(*e));
+                d.map(|d| u32::from(d) * u32::from(d)).sum::<u32>()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn exact() {
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(3, 1);
+        r.set_pixel(1, 0, SepSRgba8::new(0xFF, 0x80, 0x40));
+        r.set_pixel(2, 0, SepSRgba8::new(0x40, 0x80, 0xFF));
+        let (p, i) = quantize(&r, 4, Quantize::Octree, None);
+        assert_eq!(p.len(), 3);
+        for (c, i) in r.as_slice().iter().zip(i.as_u8_slice()) {
+            assert_eq!(p.entry(usize::from(*i)), Some(*c));
+        }
+    }
+
+    #[test]
+    fn methods() {
+        let r = gradient();
+        let mut errors = Vec::new();
+        let methods = [Quantize::MedianCut, Quantize::Octree, Quantize::KMeans];
+        for method in methods {
+            let (p, i) = quantize(&r, 16, method, None);
+            let n = p.len();
+            assert!(n > 8 && n <= 16, "{:?} {}", method, n);
+            errors.push(error(&r, &p, &i));
+        }
+        assert!(errors[2] <= errors[0]);
+        let (p, _) = quantize(&r, 1, Quantize::Octree, None);
+        assert_eq!(p.entry(0), Some(SepSRgba8::new(0x78, 0x78, 0x80)));
+    }
+
+    #[test]
+    fn dithered() {
+        let r = RasterBuilder::<SepSRgba8>::new()
+            .with_color(16, 16, SepSRgba8::new(0x80, 0x80, 0x80));
+        let mut p = Palette::new(2);
+        p.set_entry(SepSRgba8::new(0x00, 0x00, 0x00));
+        p.set_entry(SepSRgba8::new(0xFF, 0xFF, 0xFF));
+        let i = remap(&r, &p, None);
+        assert_eq!(i.as_u8_slice(), &[1; 256][..]);
+        for method in [Dither::Bayer, Dither::FloydSteinberg, Dither::BlueNoise]
+        {
+            let i = remap(&r, &p, Some(method));
+            let white = i.as_u8_slice().iter().filter(|i| **i == 1).count();
+            assert!(white > 112 && white < 144, "{:?} {}", method, white);
+        }
+    }
+}
@@ -3,58 +3,688 @@
 // Copyright (c) 2017-2019  Douglas P Lau
 // Copyright (c) 2020  Jeron Aldaron Lau
 //
-use crate::Blend;
-use pix::{Channel, Format, Raster};
+use crate::blend::mul;
+use crate::mask::{invert_slice, mask_slice};
+use crate::resize::{shift, to_format};
+use crate::{
+    Blend, BlendMode, Filter, MaskOp, Placement, PorterDuff, Rect, Rounding,
+    Transform,
+};
+use pix::{Alpha, Ch32, Channel, Format, Mask, Raster};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "rayon")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Minimum number of rows for each parallel task.
+#[cfg(feature = "rayon")]
+static MIN_ROWS_PER_TASK: AtomicUsize = AtomicUsize::new(16);
+
+/// Get the minimum number of rows for each parallel task.
+#[cfg(feature = "rayon")]
+pub fn min_rows_per_task() -> usize {
+    MIN_ROWS_PER_TASK.load(Ordering::Relaxed)
+}
+
+/// Set the minimum number of rows for each parallel task.
+///
+/// Raster operations split rows between threads, but splitting small rasters
+/// costs more than it saves.  The default is 16 rows.
+///
+/// * `rows` Minimum rows per task (at least 1).
+#[cfg(feature = "rayon")]
+pub fn set_min_rows_per_task(rows: usize) {
+    MIN_ROWS_PER_TASK.store(rows.max(1), Ordering::Relaxed);
+}
+
+/// Number of pixels which parallel chunks of a whole raster are aligned to.
+///
+/// This is a multiple of the pixels handled at once by every SIMD kernel,
+/// so chunks are split between SIMD and fallback code at the same place as
+/// one call for the whole raster.
+#[cfg(feature = "rayon")]
+const CHUNK_ALIGN: usize = 64;
+
+/// Types which can be shared between threads, when needed.
+///
+/// With the `rayon` feature, this requires `Send + Sync`; otherwise, it is
+/// implemented for all types.
+#[cfg(feature = "rayon")]
+pub trait Parallel: Send + Sync {}
+
+#[cfg(feature = "rayon")]
+impl<T: Send + Sync> Parallel for T {}
+
+/// Types which can be shared between threads, when needed.
+///
+/// With the `rayon` feature, this requires `Send + Sync`; otherwise, it is
+/// implemented for all types.
+#[cfg(not(feature = "rayon"))]
+pub trait Parallel {}
+
+#[cfg(not(feature = "rayon"))]
+impl<T> Parallel for T {}
 
 /// Blend targets with `over` operation.
 ///
 /// * `dst` Destination target.
 /// * `src` Source target.
 /// * `clr` Default blend color.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
 /// * `x` Left position of source on destination.
 /// * `y` Top position of source on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source is
+/// positioned off an edge.
 pub fn raster_over<A, B, C, H>(
     dst: &mut Raster<A>,
     src: &Raster<B>,
     clr: C,
+    opacity: H,
+    rnd: Rounding,
     x: i32,
     y: i32,
-) where
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + Parallel,
+{
+    let clr: A = clr.into();
+    raster_rows(dst, src, x, y, |d, s| A::over_slice(d, s, clr, opacity, rnd))
+}
+
+/// Blend a rectangle of a source target with `over` operation.
+///
+/// Only destination pixels within the clip rectangle are affected.  This
+/// can blit a cell of a sprite atlas, or redraw a dirty region.
+///
+/// * `dst` Destination target.
+/// * `clip` Clip rectangle on destination.
+/// * `src` Source target.
+/// * `place` Placement of source rectangle on destination.
+/// * `clr` Default blend color.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+///
+/// Returns the affected destination rectangle, or `None` if the source
+/// rectangle is positioned off an edge or clipped.
+pub fn raster_over_rect<A, B, C, H, R, P>(
+    dst: &mut Raster<A>,
+    clip: R,
+    src: &Raster<B>,
+    place: P,
+    clr: C,
+    opacity: H,
+    rnd: Rounding,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + Parallel,
+    R: Into<Rect>,
+    P: Into<Placement>,
+{
+    let clr: A = clr.into();
+    raster_rows_rect(dst, clip.into(), src, place.into(), |d, s| {
+        A::over_slice(d, s, clr, opacity, rnd)
+    })
+}
+
+/// Blend targets with `over` operation, in linear light.
+///
+/// sRGB targets are decoded to linear intensity, blended, and encoded again.
+/// This is slower than `raster_over`, but antialiased edges are not too
+/// dark.  Targets which are not sRGB are blended the same as `raster_over`.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `clr` Default blend color.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `x` Left position of source on destination.
+/// * `y` Top position of source on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source is
+/// positioned off an edge.
+pub fn raster_over_linear<A, B, C, H>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    clr: C,
+    opacity: H,
+    rnd: Rounding,
+    x: i32,
+    y: i32,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H> + From<Ch32>,
+    H: Channel + From<A::Chan> + Parallel,
+    Ch32: From<A::Chan> + From<H>,
+{
+    let clr: A = clr.into();
+    raster_rows(dst, src, x, y, |d, s| {
+        A::over_linear_slice(d, s, clr, opacity, rnd)
+    })
+}
+
+/// Blend targets with `over` operation, clipped by a mask.
+///
+/// Unlike `raster_over`, the source has full color, and each pixel is
+/// scaled by the coverage of a separate mask, like a layer mask.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `mask` Mask target (alpha is coverage of source).
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `x` Left position of source on destination.
+/// * `y` Top position of source on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source is
+/// positioned off an edge.
+///
+/// # Panics
+///
+/// Panics if the source and mask are not the same size.
+pub fn raster_over_masked<A, B, M, H>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    mask: &Raster<M>,
+    opacity: H,
+    rnd: Rounding,
+    x: i32,
+    y: i32,
+) -> Option<Rect>
+where
+    A: Blend + Parallel,
+    B: Format<Chan = H> + Parallel,
+    M: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + Parallel,
+{
+    assert!(
+        src.width() == mask.width() && src.height() == mask.height(),
+        "source and mask sizes do not match"
+    );
+    let (s, m) = (src.as_slice(), mask.as_slice());
+    let (clip, bounds) = (Rect::from(&*dst), Rect::from(src));
+    raster_rows_index(dst, clip, bounds, bounds, x, y, |row, i| {
+        let n = row.len();
+        A::over_masked_slice(row, &s[i..i + n], &m[i..i + n], opacity, rnd)
+    })
+}
+
+/// Blend targets with `over` operation, at a sub-pixel position.
+///
+/// The source is shifted by the fractional part of the position with
+/// bilinear filtering, so it does not snap to whole pixels.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `clr` Default blend color.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `x` Left position of source on destination.
+/// * `y` Top position of source on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source is
+/// positioned off an edge.
+pub fn raster_over_subpixel<A, B, C, H>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    clr: C,
+    opacity: H,
+    rnd: Rounding,
+    x: f32,
+    y: f32,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + From<Ch32> + Parallel,
+    Ch32: From<H>,
+{
+    let (ix, iy) = (x.floor(), y.floor());
+    let (fx, fy) = (x - ix, y - iy);
+    let (ix, iy) = (ix as i32, iy as i32);
+    if fx == 0.0 && fy == 0.0 {
+        raster_over(dst, src, clr, opacity, rnd, ix, iy)
+    } else {
+        raster_over(dst, &shift(src, fx, fy), clr, opacity, rnd, ix, iy)
+    }
+}
+
+/// Blend targets with `over` operation, through an affine transform.
+///
+/// Each destination pixel is mapped back to the source, which is sampled
+/// with a filter.  Pixels outside of the source are transparent, so edges
+/// are antialiased.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `clr` Default blend color.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `xform` Transform from source to destination.
+/// * `filter` Sampling filter (`Nearest` or `Bilinear` are fastest).
+///
+/// Returns the affected destination rectangle, or `None` if the source is
+/// transformed off an edge, or the transform is not invertible.
+pub fn raster_over_transformed<A, B, C, H>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    clr: C,
+    opacity: H,
+    rnd: Rounding,
+    xform: Transform,
+    filter: Filter,
+) -> Option<Rect>
+where
     A: Blend + From<C>,
     B: Format<Chan = H>,
     A::Chan: From<H>,
-    H: Channel + From<A::Chan>,
+    H: Channel + From<A::Chan> + From<Ch32>,
+    Ch32: From<H>,
 {
     let clr: A = clr.into();
-    if x == 0
-        && dst.width() == src.width()
-        && y == 0
-        && dst.height() == src.height()
-    {
-        A::over_slice(dst.as_slice_mut(), src.as_slice(), clr);
-        return;
+    let inv = xform.inverse()?;
+    let drect = transformed_rect(src, xform, filter.support())
+        .intersection(Rect::from(&*dst));
+    if drect.is_empty() {
+        return None;
+    }
+    let dw = dst.width() as usize;
+    let dx = drect.x as usize;
+    let dy = drect.y as usize;
+    let w = drect.width as usize;
+    let h = drect.height as usize;
+    let mut samples = Vec::with_capacity(w);
+    for y in dy..dy + h {
+        samples.clear();
+        for x in dx..dx + w {
+            let (sx, sy) = inv.transform(x as f32 + 0.5, y as f32 + 0.5);
+            let p = filter.sample_point(src, sx, sy);
+            samples.push(to_format::<B>(p));
+        }
+        let row = &mut dst.as_slice_mut()[y * dw + dx..y * dw + dx + w];
+        A::over_fallback(row, &samples, clr, opacity, rnd);
+    }
+    Some(drect)
+}
+
+/// Get bounding rectangle of a transformed raster.
+///
+/// * `src` Source raster.
+/// * `xform` Transform from source to destination.
+/// * `support` Filter support radius.
+fn transformed_rect<B: Format>(
+    src: &Raster<B>,
+    xform: Transform,
+    support: f32,
+) -> Rect {
+    let x1 = src.width() as f32 + support;
+    let y1 = src.height() as f32 + support;
+    let s = -support;
+    let corners = [(s, s), (x1, s), (s, y1), (x1, y1)];
+    let pts = corners.iter().map(|(x, y)| xform.transform(*x, *y));
+    let (mut x0, mut y0) = (f32::MAX, f32::MAX);
+    let (mut x1, mut y1) = (f32::MIN, f32::MIN);
+    for (x, y) in pts {
+        x0 = x0.min(x);
+        y0 = y0.min(y);
+        x1 = x1.max(x);
+        y1 = y1.max(y);
+    }
+    // only pixels with centers inside the bounds are affected
+    let clamp = |v: f32| v.max(i32::MIN as f32).min(i32::MAX as f32);
+    let first = |v: f32| clamp((v - 0.5).floor() + 1.0);
+    let end = |v: f32| clamp((v - 0.5).ceil());
+    let (x0, y0, x1, y1) = (first(x0), first(y0), end(x1), end(y1));
+    let (w, h) = ((x1 - x0).max(0.0), (y1 - y0).max(0.0));
+    Rect::new(x0 as i32, y0 as i32, w as u32, h as u32)
+}
+
+/// Composite targets with a Porter-Duff operation.
+///
+/// Only destination pixels covered by the source are affected.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `clr` Default blend color.
+/// * `op` Compositing operation.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `place` Placement of source rectangle on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source
+/// rectangle is positioned off an edge.
+pub fn raster_composite<A, B, C, H, P>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    clr: C,
+    op: PorterDuff,
+    opacity: H,
+    rnd: Rounding,
+    place: P,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + Parallel,
+    P: Into<Placement>,
+{
+    let clr: A = clr.into();
+    let clip = Rect::from(&*dst);
+    raster_rows_rect(dst, clip, src, place.into(), |d, s| {
+        A::composite_slice(d, s, clr, op, opacity, rnd)
+    })
+}
+
+/// Blend targets with a blend mode.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `clr` Default blend color.
+/// * `mode` Blend mode.
+/// * `opacity` Opacity of source (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `place` Placement of source rectangle on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source
+/// rectangle is positioned off an edge.
+pub fn raster_blend<A, B, C, H, P>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    clr: C,
+    mode: BlendMode,
+    opacity: H,
+    rnd: Rounding,
+    place: P,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + From<Ch32> + Parallel,
+    Ch32: From<H>,
+    P: Into<Placement>,
+{
+    let clr: A = clr.into();
+    let clip = Rect::from(&*dst);
+    raster_rows_rect(dst, clip, src, place.into(), |d, s| {
+        A::blend_slice(d, s, clr, mode, opacity, rnd)
+    })
+}
+
+/// Combine a mask target with another target.
+///
+/// Only destination pixels covered by the source are affected.
+///
+/// * `dst` Destination mask target.
+/// * `src` Source target (alpha channel is used).
+/// * `op` Mask operation.
+/// * `rnd` Rounding policy.
+/// * `x` Left position of source on destination.
+/// * `y` Top position of source on destination.
+///
+/// Returns the affected destination rectangle, or `None` if the source is
+/// positioned off an edge.
+pub fn raster_mask_op<A, B, C, H>(
+    dst: &mut Raster<Mask<A>>,
+    src: &Raster<B>,
+    op: MaskOp,
+    rnd: Rounding,
+    x: i32,
+    y: i32,
+) -> Option<Rect>
+where
+    C: Channel + From<H>,
+    A: Alpha<Chan = C> + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    H: Channel + From<C>,
+{
+    raster_rows(dst, src, x, y, |d, s| mask_slice(d, s, op, rnd))
+}
+
+/// Invert a mask target.
+///
+/// * `dst` Mask target.
+pub fn raster_invert<A, C>(dst: &mut Raster<Mask<A>>)
+where
+    C: Channel,
+    A: Alpha<Chan = C> + From<C>,
+{
+    invert_slice(dst.as_slice_mut());
+}
+
+/// Interpolate linearly between two targets, for cross-fades.
+///
+/// * `dst` Destination target.
+/// * `a` First source target.
+/// * `b` Second source target.
+/// * `t` Interpolation from `a` (`MIN`) to `b` (`MAX`).
+/// * `opacity` Opacity of `b` (`MAX` is opaque), multiplied with `t`.
+///
+/// # Panics
+///
+/// Panics if the targets are not all the same size.
+pub fn raster_lerp<A, H>(
+    dst: &mut Raster<A>,
+    a: &Raster<A>,
+    b: &Raster<A>,
+    t: H,
+    opacity: H,
+) where
+    A: Blend,
+    A::Chan: From<H>,
+{
+    assert_same_size(dst, a, b);
+    let o = A::Chan::from(opacity);
+    let t = mul(A::Chan::from(t), o, Rounding::Exact);
+    let src = a.as_slice().iter().zip(b.as_slice());
+    for (d, (a, b)) in dst.as_slice_mut().iter_mut().zip(src) {
+        *d = A::lerp(*a, *b, t);
     }
-    if x + (src.width() as i32) < 0 || x >= dst.width() as i32 {
-        return; // positioned off left or right edge
+}
+
+/// Interpolate linearly between two targets, using a mask for wipe effects.
+///
+/// * `dst` Destination target.
+/// * `a` First source target.
+/// * `b` Second source target.
+/// * `mask` Mask target, interpolating from `a` (`MIN`) to `b` (`MAX`).
+/// * `opacity` Opacity of `b` (`MAX` is opaque), multiplied with the mask.
+///
+/// # Panics
+///
+/// Panics if the targets are not all the same size.
+pub fn raster_lerp_masked<A, B, H>(
+    dst: &mut Raster<A>,
+    a: &Raster<A>,
+    b: &Raster<A>,
+    mask: &Raster<B>,
+    opacity: H,
+) where
+    A: Blend,
+    B: Format<Chan = H>,
+    A::Chan: From<H>,
+    H: Channel,
+{
+    assert_same_size(dst, a, b);
+    assert_same_size(dst, a, mask);
+    let src = a.as_slice().iter().zip(b.as_slice());
+    let src = src.zip(mask.as_slice());
+    let o = A::Chan::from(opacity);
+    for (d, ((a, b), m)) in dst.as_slice_mut().iter_mut().zip(src) {
+        let t = mul(A::Chan::from(m.rgba()[3]), o, Rounding::Exact);
+        *d = A::lerp(*a, *b, t);
     }
-    if y + (src.height() as i32) < 0 || y >= dst.height() as i32 {
-        return; // positioned off top or bottom edge
+}
+
+/// Check that three targets are the same size.
+fn assert_same_size<A, B, C>(a: &Raster<A>, b: &Raster<B>, c: &Raster<C>)
+where
+    A: Format,
+    B: Format,
+    C: Format,
+{
+    assert!(
+        a.width() == b.width()
+            && a.width() == c.width()
+            && a.height() == b.height()
+            && a.height() == c.height(),
+        "raster sizes do not match"
+    );
+}
+
+/// Apply an operation to each row of overlapping pixels.
+///
+/// * `dst` Destination target.
+/// * `src` Source target.
+/// * `x` Left position of source on destination.
+/// * `y` Top position of source on destination.
+/// * `op` Operation on destination and source rows.
+///
+/// Returns the affected destination rectangle.
+fn raster_rows<A, B, F>(
+    dst: &mut Raster<A>,
+    src: &Raster<B>,
+    x: i32,
+    y: i32,
+    op: F,
+) -> Option<Rect>
+where
+    A: Format + Parallel,
+    B: Format + Parallel,
+    F: Fn(&mut [A], &[B]) + Parallel,
+{
+    let clip = Rect::from(&*dst);
+    raster_rows_rect(dst, clip, src, (src, x, y).into(), op)
+}
+
+/// Apply an operation to each row of overlapping pixels within rectangles.
+///
+/// * `dst` Destination target.
+/// * `clip` Clip rectangle on destination.
+/// * `src` Source target.
+/// * `place` Placement of source rectangle on destination.
+/// * `op` Operation on destination and source rows.
+///
+/// Returns the affected destination rectangle.
+pub(crate) fn raster_rows_rect<A, B, F>(
+    dst: &mut Raster<A>,
+    clip: Rect,
+    src: &Raster<B>,
+    place: Placement,
+    op: F,
+) -> Option<Rect>
+where
+    A: Format + Parallel,
+    B: Format + Parallel,
+    F: Fn(&mut [A], &[B]) + Parallel,
+{
+    let m = src.as_slice();
+    let bounds = Rect::from(src);
+    let Placement { rect, x, y } = place;
+    raster_rows_index(dst, clip, bounds, rect, x, y, |row, i| {
+        op(row, &m[i..i + row.len()])
+    })
+}
+
+/// Apply an operation to each row of pixels overlapping a source area.
+///
+/// With the `rayon` feature, rows are split between threads, with at least
+/// `min_rows_per_task` rows for each task.
+///
+/// * `dst` Destination target.
+/// * `clip` Clip rectangle on destination.
+/// * `bounds` Bounds of source (at origin).
+/// * `rect` Rectangle of source.
+/// * `x` Left position of source rectangle on destination.
+/// * `y` Top position of source rectangle on destination.
+/// * `op` Operation on destination row and index of first source pixel.
+///
+/// Returns the affected destination rectangle.
+fn raster_rows_index<A, F>(
+    dst: &mut Raster<A>,
+    clip: Rect,
+    bounds: Rect,
+    rect: Rect,
+    x: i32,
+    y: i32,
+    op: F,
+) -> Option<Rect>
+where
+    A: Format + Parallel,
+    F: Fn(&mut [A], usize) + Parallel,
+{
+    // clip source rectangle to source, then position it on destination
+    let srect = rect.intersection(bounds);
+    let px = offset(x, srect.x, rect.x);
+    let py = offset(y, srect.y, rect.y);
+    let placed = Rect::new(px, py, srect.width, srect.height);
+    let drect = placed.intersection(clip).intersection(Rect::from(&*dst));
+    if drect.is_empty() {
+        return None; // positioned off an edge, or clipped
     }
-    let mx = 0.max(-x) as usize;
-    let my = 0.max(-y) as u32;
-    let mw = src.width() as usize;
-    let dx = 0.max(x) as usize;
-    let dy = 0.max(y) as u32;
+    let w = drect.width as usize;
+    let h = drect.height as usize;
     let dw = dst.width() as usize;
-    let h = (dst.height() - dy).min(src.height() - my);
-    for yi in 0..h {
-        let mut row = &mut dst.as_slice_row_mut(dy + yi)[dx..dw];
-        let m = &src.as_slice_row(my + yi)[mx..mw];
-        A::over_slice(&mut row, m, clr);
+    let mw = bounds.width as usize;
+    if w == dw
+        && w == mw
+        && h == dst.height() as usize
+        && h == bounds.height as usize
+    {
+        let pix = dst.as_slice_mut();
+        #[cfg(feature = "rayon")]
+        {
+            let n = min_rows_per_task() * dw;
+            let chunk = n + (CHUNK_ALIGN - n % CHUNK_ALIGN) % CHUNK_ALIGN;
+            pix.par_chunks_mut(chunk)
+                .enumerate()
+                .for_each(|(i, c)| op(c, i * chunk));
+        }
+        #[cfg(not(feature = "rayon"))]
+        op(pix, 0);
+        return Some(drect);
+    }
+    let dx = drect.x as usize;
+    let dy = drect.y as usize;
+    let mx = (i64::from(srect.x) + i64::from(drect.x) - i64::from(px)) as usize;
+    let my = (i64::from(srect.y) + i64::from(drect.y) - i64::from(py)) as usize;
+    let rows = &mut dst.as_slice_mut()[dy * dw..(dy + h) * dw];
+    #[cfg(feature = "rayon")]
+    rows.par_chunks_mut(dw)
+        .enumerate()
+        .with_min_len(min_rows_per_task())
+        .for_each(|(i, row)| op(&mut row[dx..dx + w], (my + i) * mw + mx));
+    #[cfg(not(feature = "rayon"))]
+    for (i, row) in rows.chunks_mut(dw).enumerate() {
+        op(&mut row[dx..dx + w], (my + i) * mw + mx);
     }
+    Some(drect)
+}
+
+/// Offset a position by the clipped amount of a rectangle.
+///
+/// * `pos` Unclipped position.
+/// * `clipped` Clipped rectangle side.
+/// * `side` Unclipped rectangle side.
+fn offset(pos: i32, clipped: i32, side: i32) -> i32 {
+    let p = i64::from(pos) + i64::from(clipped) - i64::from(side);
+    p.max(i32::MIN.into()).min(i32::MAX.into()) as i32
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_conversion)]
 mod test {
     use super::*;
     use pix::*;
@@ -66,7 +696,7 @@ mod test {
         m.set_pixel(1, 1, 0x80);
         m.set_pixel(2, 2, 0x40);
         let c: AssocSRgba8 = AssocSRgba8::new(0xFF, 0x80, 0x40).into();
-        raster_over(&mut r, &m, c, 0, 0);
+        raster_over(&mut r, &m, c, Ch8::MAX, Rounding::Exact, 0, 0);
         #[rustfmt::skip]
         let v = [
             0xFF, 0x80, 0x40, 0xFF,
@@ -81,13 +711,7 @@ mod test {
             0x00, 0x00, 0x00, 0x00,
             0x40, 0x20, 0x10, 0x40,
         ];
-        let left = r.as_u8_slice();
-        // NOTE: fallback version     SIMD version
-        assert!(left[0] == 0xFF || left[0] == 0xFE);
-        assert!(left[1] == 0x80 || left[1] == 0x7F);
-        assert!(left[2] == 0x40 || left[2] == 0x3F);
-        assert!(left[3] == 0xFF || left[3] == 0xFE);
-        assert_eq!(&left[4..], &v[4..]);
+        assert_eq!(r.as_u8_slice(), &v[..]);
     }
     #[test]
     fn smaller_mask() {
@@ -98,7 +722,7 @@ mod test {
         m.set_pixel(1, 0, 0x80);
         m.set_pixel(0, 1, 0x40);
         m.set_pixel(1, 1, 0x20);
-        raster_over(&mut r, &m, c, 1, 1);
+        raster_over(&mut r, &m, c, Ch8::MAX, Rounding::Fast, 1, 1);
         #[rustfmt::skip]
         let v = [
             0x00, 0x00, 0x00, 0x00,
@@ -121,7 +745,7 @@ mod test {
         let m =
             RasterBuilder::<Mask8>::new().with_color(2, 2, Mask8::new(0xFF));
         let c: AssocSRgba8 = AssocSRgba8::new(0x20, 0x40, 0x80).into();
-        raster_over(&mut r, &m, c, -1, -1);
+        raster_over(&mut r, &m, c, Ch8::MAX, Rounding::Fast, -1, -1);
         #[rustfmt::skip]
         let v = [
             0x20, 0x40, 0x80, 0xFF,
@@ -147,7 +771,7 @@ mod test {
         m.set_pixel(1, 0, 0xFF);
         m.set_pixel(0, 1, 0xFF);
         m.set_pixel(1, 1, 0xFF);
-        raster_over(&mut r, &m, c, 2, 2);
+        raster_over(&mut r, &m, c, Ch8::MAX, Rounding::Fast, 2, 2);
         #[rustfmt::skip]
         let v = [
             0x00, 0x00, 0x00, 0x00,
@@ -164,4 +788,291 @@ mod test {
         ];
         assert_eq!(r.as_u8_slice(), &v[..]);
     }
+    #[test]
+    fn atlas_cell() {
+        let rnd = Rounding::Fast;
+        let mut r = RasterBuilder::<AssocSRgba8>::new().with_clear(3, 3);
+        let mut m = RasterBuilder::<Mask8>::new().with_clear(4, 2);
+        let c: AssocSRgba8 = AssocSRgba8::new(0x20, 0x40, 0x80);
+        m.set_pixel(2, 0, 0xFF);
+        m.set_pixel(3, 0, 0xFF);
+        m.set_pixel(2, 1, 0xFF);
+        m.set_pixel(3, 1, 0xFF);
+        let (clip, rect) = ((0, 0, 2, 3), (2, 0, 2, 2));
+        raster_over_rect(&mut r, clip, &m, (rect, 1, 1), c, Ch8::MAX, rnd);
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0x20, 0x40, 0x80, 0xFF,
+            0x00, 0x00, 0x00, 0x00,
+
+            0x00, 0x00, 0x00, 0x00,
+            0x20, 0x40, 0x80, 0xFF,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+    }
+    #[test]
+    fn affected_rect() {
+        let rnd = Rounding::Fast;
+        let mut r = RasterBuilder::<AssocSRgba8>::new().with_clear(3, 3);
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(2, 2, Mask8::new(0xFF));
+        let c: AssocSRgba8 = AssocSRgba8::new(0x20, 0x40, 0x80);
+        let rect = raster_over(&mut r, &m, c, Ch8::MAX, rnd, -1, -1);
+        assert_eq!(rect, Some(Rect::new(0, 0, 1, 1)));
+        let rect = raster_over(&mut r, &m, c, Ch8::MAX, rnd, 2, 1);
+        assert_eq!(rect, Some(Rect::new(2, 1, 1, 2)));
+        assert_eq!(raster_over(&mut r, &m, c, Ch8::MAX, rnd, 3, 0), None);
+        assert_eq!(raster_over(&mut r, &m, c, Ch8::MAX, rnd, 0, -2), None);
+        let (clip, place) = ((1, 0, 1, 3), ((1, 1, 1, 1), 0, 0));
+        let rect = raster_over_rect(&mut r, clip, &m, place, c, Ch8::MAX, rnd);
+        assert_eq!(rect, None);
+    }
+    #[test]
+    fn subpixel() {
+        let rnd = Rounding::Fast;
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(1, 1, Mask8::new(0xFF));
+        let c = SepLGray8::new(0xFF);
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(4, 1);
+        let o = Ch8::MAX;
+        let rect = raster_over_subpixel(&mut r, &m, c, o, rnd, 1.25, 0.0);
+        assert_eq!(rect, Some(Rect::new(1, 0, 2, 1)));
+        assert_eq!(r.as_u8_slice(), &[0x00, 0xBF, 0x40, 0x00]);
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(2, 2);
+        let rect = raster_over_subpixel(&mut r, &m, c, o, rnd, -0.5, -0.5);
+        assert_eq!(rect, Some(Rect::new(0, 0, 1, 1)));
+        assert_eq!(r.as_u8_slice(), &[0x40, 0x00, 0x00, 0x00]);
+    }
+    #[test]
+    fn transformed() {
+        let rnd = Rounding::Fast;
+        let mut m = RasterBuilder::<Mask8>::new().with_clear(2, 2);
+        m.set_pixel(0, 0, Mask8::new(0xFF));
+        m.set_pixel(1, 1, Mask8::new(0x80));
+        let c = SepLGray8::new(0xFF);
+        let mut r0 = RasterBuilder::<SepLGray8>::new().with_clear(4, 4);
+        let mut r1 = RasterBuilder::<SepLGray8>::new().with_clear(4, 4);
+        raster_over(&mut r0, &m, c, Ch8::MAX, rnd, 1, 2);
+        let t = Transform::new_translate(1.0, 2.0);
+        let f = Filter::Nearest;
+        let rect = raster_over_transformed(&mut r1, &m, c, Ch8::MAX, rnd, t, f);
+        assert_eq!(rect, Some(Rect::new(1, 2, 2, 2)));
+        assert_eq!(r0.as_u8_slice(), r1.as_u8_slice());
+        let t = Transform::new_scale(0.0, 1.0);
+        let rect = raster_over_transformed(&mut r1, &m, c, Ch8::MAX, rnd, t, f);
+        assert_eq!(rect, None);
+    }
+    #[test]
+    fn transformed_edges() {
+        let rnd = Rounding::Fast;
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(1, 1, Mask8::new(0xFF));
+        let c = SepLGray8::new(0xFF);
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(4, 4);
+        let t = Transform::new_scale(2.0, 2.0).translate(1.0, 1.0);
+        let f = Filter::Bilinear;
+        raster_over_transformed(&mut r, &m, c, Ch8::MAX, rnd, t, f);
+        #[rustfmt::skip]
+        let v = [
+            0x10, 0x30, 0x30, 0x10,
+            0x30, 0x8F, 0x8F, 0x30,
+            0x30, 0x8F, 0x8F, 0x30,
+            0x10, 0x30, 0x30, 0x10,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(4, 1);
+        let t = Transform::new_translate(1.5, 0.0);
+        let f = Filter::Nearest;
+        raster_over_transformed(&mut r, &m, c, Ch8::MAX, rnd, t, f);
+        assert_eq!(r.as_u8_slice(), &[0x00, 0x80, 0x80, 0x00]);
+    }
+    #[test]
+    fn mask_offset() {
+        let rnd = Rounding::Fast;
+        let mut r = RasterBuilder::<Mask8>::new().with_clear(3, 2);
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(2, 2, Mask8::new(0xFF));
+        let rect = raster_mask_op(&mut r, &m, MaskOp::Union, rnd, 2, -1);
+        assert_eq!(rect, Some(Rect::new(2, 0, 1, 1)));
+        raster_invert(&mut r);
+        let rect = raster_mask_op(&mut r, &m, MaskOp::Subtract, rnd, -1, 1);
+        assert_eq!(rect, Some(Rect::new(0, 1, 1, 1)));
+        assert_eq!(r.as_u8_slice(), &[0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    #[test]
+    fn over_masked() {
+        let rnd = Rounding::Fast;
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(3, 1);
+        let s = RasterBuilder::<SepSRgba8>::new()
+            .with_color(2, 1, SepSRgba8::new(0xFF, 0x80, 0x00));
+        let mut m = RasterBuilder::<Mask8>::new().with_clear(2, 1);
+        m.set_pixel(0, 0, Mask8::new(0xFF));
+        m.set_pixel(1, 0, Mask8::new(0x80));
+        let rect = raster_over_masked(&mut r, &s, &m, Ch8::MAX, rnd, 1, 0);
+        assert_eq!(rect, Some(Rect::new(1, 0, 2, 1)));
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0x00, 0x00,
+            0xFF, 0x80, 0x00, 0xFF,
+            0xFF, 0x80, 0x00, 0x80,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+        let mut g = RasterBuilder::<SepLGray8>::new().with_clear(2, 1);
+        let s = RasterBuilder::<SepLGray8>::new()
+            .with_color(2, 1, SepLGray8::new(0x80));
+        raster_over_masked(&mut g, &s, &m, Ch8::MAX, rnd, 0, 0);
+        assert_eq!(g.as_u8_slice(), &[0x80, 0x40]);
+    }
+    #[test]
+    #[should_panic(expected = "source and mask sizes do not match")]
+    fn over_masked_size() {
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(3, 1);
+        let s = RasterBuilder::<SepSRgba8>::new().with_clear(2, 1);
+        let m = RasterBuilder::<Mask8>::new().with_clear(2, 2);
+        raster_over_masked(&mut r, &s, &m, Ch8::MAX, Rounding::Fast, 0, 0);
+    }
+    #[test]
+    fn opacity() {
+        let rnd = Rounding::Fast;
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(2, 1);
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(1, 1, Mask8::new(0xFF));
+        let c = SepLGray8::new(0xFF);
+        raster_over(&mut r, &m, c, Ch8::new(0x80), rnd, 0, 0);
+        let s = RasterBuilder::<SepLGray8>::new()
+            .with_color(1, 1, SepLGray8::new(0x80));
+        raster_over_masked(&mut r, &s, &m, Ch8::new(0x80), rnd, 1, 0);
+        assert_eq!(r.as_u8_slice(), &[0x80, 0x40]);
+        let mut r = RasterBuilder::<AssocSRgba8>::new().with_clear(1, 1);
+        let c = AssocSRgba8::new(0xFF, 0x80, 0x00);
+        raster_over(&mut r, &m, c, Ch8::MIN, rnd, 0, 0);
+        assert_eq!(r.as_u8_slice(), &[0x00; 4]);
+    }
+    #[test]
+    fn cross_fade() {
+        let a = RasterBuilder::<SepSRgba8>::new().with_color(
+            2,
+            1,
+            SepSRgba8::with_alpha(0xFF, 0x00, 0x00, 0x00),
+        );
+        let b = RasterBuilder::<SepSRgba8>::new().with_color(
+            2,
+            1,
+            SepSRgba8::new(0x00, 0x00, 0xFF),
+        );
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(2, 1);
+        raster_lerp(&mut r, &a, &b, 0x80, 0xFF);
+        // transparent red has no effect with associated alpha
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0xFF, 0x80,
+            0x00, 0x00, 0xFF, 0x80,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+        let mut m = RasterBuilder::<Mask8>::new().with_clear(2, 1);
+        m.set_pixel(1, 0, 0xFF);
+        raster_lerp_masked(&mut r, &a, &b, &m, Ch8::MAX);
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xFF, 0xFF,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+        raster_lerp(&mut r, &a, &b, 0xFF, 0x80);
+        raster_lerp_masked(&mut r, &a, &b, &m, Ch8::new(0x80));
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xFF, 0x80,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+    }
+    #[test]
+    fn porter_duff() {
+        let mut r = RasterBuilder::<AssocSRgba8>::new().with_color(
+            3,
+            1,
+            AssocSRgba8::with_alpha(0x20, 0x40, 0x80, 0x80),
+        );
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(2, 1, Mask8::new(0xFF));
+        let c = AssocSRgba8::new(0x10, 0x20, 0x30);
+        let (o, rnd) = (Ch8::MAX, Rounding::Fast);
+        let op = PorterDuff::SrcIn;
+        let rect = raster_composite(&mut r, &m, c, op, o, rnd, (&m, -1, 0));
+        assert_eq!(rect, Some(Rect::new(0, 0, 1, 1)));
+        let op = PorterDuff::DstOut;
+        let rect = raster_composite(&mut r, &m, c, op, o, rnd, (&m, 2, 0));
+        assert_eq!(rect, Some(Rect::new(2, 0, 1, 1)));
+        let op = PorterDuff::Src;
+        let rect = raster_composite(&mut r, &m, c, op, o, rnd, (&m, 3, 0));
+        assert_eq!(rect, None);
+        #[rustfmt::skip]
+        let v = [
+            0x08, 0x10, 0x18, 0x80,
+            0x20, 0x40, 0x80, 0x80,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+    }
+    #[test]
+    fn blend_multiply() {
+        let mut r = RasterBuilder::<AssocSRgba8>::new().with_color(
+            2,
+            1,
+            AssocSRgba8::new(0xFF, 0x80, 0x40),
+        );
+        let mut m = RasterBuilder::<Mask8>::new().with_clear(2, 1);
+        m.set_pixel(0, 0, 0xFF);
+        let c = AssocSRgba8::new(0x80, 0x80, 0xFF);
+        let (mode, rnd) = (BlendMode::Multiply, Rounding::Fast);
+        let rect = raster_blend(&mut r, &m, c, mode, Ch8::MAX, rnd, (&m, 0, 0));
+        assert_eq!(rect, Some(Rect::new(0, 0, 2, 1)));
+        #[rustfmt::skip]
+        let v = [
+            0x80, 0x40, 0x40, 0xFF,
+            0xFF, 0x80, 0x40, 0xFF,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+    }
+    /// Check that blending a whole raster matches the serial path, which
+    /// blends all pixels with one slice call.
+    fn check_serial<A, B>(w: u32, pix: Vec<A>, mask: Vec<B>, clr: A, o: B::Chan)
+    where
+        A: Blend + Format<Chan = B::Chan> + Parallel,
+        B: Format + Parallel,
+        B::Chan: Parallel,
+    {
+        let rnd = Rounding::Fast;
+        let h = pix.len() as u32 / w;
+        let m = RasterBuilder::<B>::new().with_pixels(w, h, mask);
+        let mut r = RasterBuilder::new().with_pixels(w, h, pix.clone());
+        let mut serial = RasterBuilder::new().with_pixels(w, h, pix);
+        raster_over(&mut r, &m, clr, o, rnd, 0, 0);
+        A::over_slice(serial.as_slice_mut(), m.as_slice(), clr, o, rnd);
+        assert_eq!(r.as_u8_slice(), serial.as_u8_slice());
+    }
+
+    #[test]
+    fn matches_serial() {
+        let c = AssocSRgba8::with_alpha(0x40, 0x80, 0x60, 0x80);
+        for w in [3, 5, 7, 33].iter() {
+            let v: Vec<u8> = (0..w * 37).map(|i| (i * 29) as u8).collect();
+            let p = |v: &u8| AssocSRgba8::with_alpha(v / 2, v / 3, v / 4, *v);
+            let m = |v: &u8| Mask8::new(v.wrapping_mul(7));
+            let pix: Vec<_> = v.iter().map(p).collect();
+            let mask: Vec<_> = v.iter().map(m).collect();
+            check_serial(*w, pix.clone(), mask.clone(), c, Ch8::MAX);
+            let pix = pix.iter().map(|p| p.convert()).collect();
+            let mask = mask.iter().map(|m| m.convert()).collect();
+            let o = Ch16::new(0xB3A1);
+            check_serial::<AssocSRgba16, Mask16>(*w, pix, mask, c.convert(), o);
+        }
+    }
 }
@@ -0,0 +1,122 @@
+// rect.rs      Rectangles for raster operations.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use pix::{Format, Raster, Region};
+
+/// A rectangular area of a raster.
+///
+/// Unlike `Region`, the position and size are public, so rectangles can be
+/// inspected after clipping.  A `Rect` can be created from a tuple of
+/// *x*, *y*, *width* and *height*.
+///
+/// ```
+/// # use pixops::Rect;
+/// let r0 = Rect::new(0, 0, 100, 100);
+/// let r1 = r0.intersection((50, 40, 360, 240));
+/// assert_eq!(r1, Rect::new(50, 40, 50, 60));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    /// Left position
+    pub x: i32,
+    /// Top position
+    pub y: i32,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+}
+
+impl From<(i32, i32, u32, u32)> for Rect {
+    fn from(r: (i32, i32, u32, u32)) -> Self {
+        Rect::new(r.0, r.1, r.2, r.3)
+    }
+}
+
+impl<F: Format> From<&Raster<F>> for Rect {
+    fn from(r: &Raster<F>) -> Self {
+        Rect::new(0, 0, r.width(), r.height())
+    }
+}
+
+impl From<Rect> for Region {
+    fn from(r: Rect) -> Self {
+        Region::new(r.x, r.y, r.width, r.height)
+    }
+}
+
+/// Placement of a source rectangle on a destination.
+///
+/// A `Placement` can be created from a tuple of source rectangle, and *x*,
+/// *y* position on the destination.  The rectangle can also be a raster, to
+/// place all of it.
+///
+/// ```
+/// # use pixops::{Placement, Rect};
+/// let p = Placement::from(((2, 0, 2, 2), 1, 1));
+/// assert_eq!(p.rect, Rect::new(2, 0, 2, 2));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Placement {
+    /// Rectangle of source
+    pub rect: Rect,
+    /// Left position of source rectangle on destination
+    pub x: i32,
+    /// Top position of source rectangle on destination
+    pub y: i32,
+}
+
+impl<R: Into<Rect>> From<(R, i32, i32)> for Placement {
+    fn from(p: (R, i32, i32)) -> Self {
+        Placement {
+            rect: p.0.into(),
+            x: p.1,
+            y: p.2,
+        }
+    }
+}
+
+impl Rect {
+    /// Create a new `Rect`
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Check if the rectangle contains no pixels
+    pub fn is_empty(self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Get intersection with another `Rect`
+    ///
+    /// If the rectangles do not overlap, the result is empty.
+    pub fn intersection<R>(self, rhs: R) -> Self
+    where
+        R: Into<Self>,
+    {
+        let rhs = rhs.into();
+        let x0 = self.x.max(rhs.x);
+        let x1 = self.right().min(rhs.right());
+        let y0 = self.y.max(rhs.y);
+        let y1 = self.bottom().min(rhs.bottom());
+        let w = (x1 - i64::from(x0)).max(0) as u32;
+        let h = (y1 - i64::from(y0)).max(0) as u32;
+        Rect::new(x0, y0, w, h)
+    }
+
+    /// Get right side
+    fn right(self) -> i64 {
+        i64::from(self.x) + i64::from(self.width)
+    }
+
+    /// Get bottom side
+    fn bottom(self) -> i64 {
+        i64::from(self.y) + i64::from(self.height)
+    }
+}
@@ -0,0 +1,542 @@
+// resize.rs    Raster resampling.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use crate::lerp::Lerp;
+use pix::{AssocLRgba32, Ch32, Format, Raster, RasterBuilder};
+use std::f32::consts::PI;
+
+/// Resampling filter for [resize](fn.resize.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Nearest pixel (no filtering)
+    Nearest,
+    /// Linear interpolation (triangle filter)
+    Bilinear,
+    /// Bicubic Catmull-Rom spline (sharp)
+    CatmullRom,
+    /// Bicubic Mitchell-Netravali filter (smooth)
+    Mitchell,
+    /// Lanczos windowed sinc, with 3 lobes
+    Lanczos3,
+}
+
+/// Maximum number of source pixels weighted along one axis of a point.
+const POINT_TAPS: usize = 7;
+
+/// Filter weights for sampling a point along one axis.
+struct PointWeights {
+    /// Index of first source pixel
+    first: usize,
+    /// Number of weighted pixels
+    len: usize,
+    /// Weights of source pixels
+    weights: [f32; POINT_TAPS],
+}
+
+impl PointWeights {
+    /// Iterate over source pixel indices and weights.
+    fn iter(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        let first = self.first;
+        self.weights[..self.len]
+            .iter()
+            .enumerate()
+            .map(move |(i, w)| (first + i, *w))
+    }
+}
+
+/// Source pixels sampled for one destination pixel.
+#[derive(Clone, Copy)]
+enum Tap {
+    /// Interpolate between two pixels
+    Lerp(usize, usize, f32),
+    /// Weighted sum of pixels (range of weights in `Taps`)
+    Weights(usize, usize),
+}
+
+/// Source taps for each destination pixel along one axis.
+#[derive(Default)]
+struct Taps {
+    /// Tap for each destination pixel
+    taps: Vec<Tap>,
+    /// Source pixel indices and weights, for all destination pixels
+    weights: Vec<(usize, f32)>,
+}
+
+impl Taps {
+    /// Get the number of destination pixels.
+    fn len(&self) -> usize {
+        self.taps.len()
+    }
+
+    /// Add a lerp tap.
+    fn push_lerp(&mut self, j0: usize, j1: usize, t: f32) {
+        self.taps.push(Tap::Lerp(j0, j1, t));
+    }
+
+    /// Add a weighted tap.
+    ///
+    /// * `weights` Source pixel indices and weights.
+    fn push_weights<I>(&mut self, weights: I)
+    where
+        I: IntoIterator<Item = (usize, f32)>,
+    {
+        let start = self.weights.len();
+        self.weights.extend(weights);
+        self.taps.push(Tap::Weights(start, self.weights.len()));
+    }
+
+    /// Sample source pixels for one destination pixel.
+    ///
+    /// * `i` Destination pixel index.
+    /// * `pixel` Function to get source pixel at an index.
+    fn sample<P>(&self, i: usize, pixel: P) -> Pixel
+    where
+        P: Fn(usize) -> Pixel,
+    {
+        match self.taps[i] {
+            Tap::Lerp(j0, j1, t) => {
+                let (p0, p1) = (pixel(j0), pixel(j1));
+                let t = Ch32::new(t);
+                let mut p = [0.0; 4];
+                for c in 0..4 {
+                    let v = Ch32::new(p0[c]).lerp(Ch32::new(p1[c]), t);
+                    p[c] = f32::from(v);
+                }
+                p
+            }
+            Tap::Weights(start, end) => {
+                let mut p = [0.0; 4];
+                for (j, w) in &self.weights[start..end] {
+                    let s = pixel(*j);
+                    for c in 0..4 {
+                        p[c] += s[c] * w;
+                    }
+                }
+                p
+            }
+        }
+    }
+}
+
+/// Pixel with associated, linear channels
+pub(crate) type Pixel = [f32; 4];
+
+impl Filter {
+    /// Get the support radius of the filter kernel
+    pub(crate) fn support(self) -> f32 {
+        match self {
+            Filter::Nearest => 0.5,
+            Filter::Bilinear => 1.0,
+            Filter::CatmullRom | Filter::Mitchell => 2.0,
+            Filter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// Evaluate the filter kernel
+    fn kernel(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Nearest => {
+                if x < 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Bilinear => (1.0 - x).max(0.0),
+            Filter::CatmullRom => cubic(0.0, 0.5, x),
+            Filter::Mitchell => cubic(1.0 / 3.0, 1.0 / 3.0, x),
+            Filter::Lanczos3 => {
+                if x < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// Get the source taps for each destination pixel along one axis.
+    ///
+    /// * `src_len` Number of source pixels.
+    /// * `dst_len` Number of destination pixels.
+    fn taps(self, src_len: usize, dst_len: usize) -> Taps {
+        let scale = src_len as f32 / dst_len as f32;
+        let last = src_len - 1;
+        let mut taps = Taps::default();
+        for i in 0..dst_len {
+            let center = (i as f32 + 0.5) * scale;
+            match self {
+                Filter::Nearest => {
+                    let j = (center as usize).min(last);
+                    taps.push_weights(Some((j, 1.0)));
+                }
+                Filter::Bilinear if scale <= 1.0 => {
+                    let x = (center - 0.5).max(0.0).min(last as f32);
+                    let j = x as usize;
+                    taps.push_lerp(j, (j + 1).min(last), x - j as f32);
+                }
+                _ => {
+                    let fscale = scale.max(1.0);
+                    self.weights(&mut taps, center - 0.5, fscale, last)
+                }
+            }
+        }
+        taps
+    }
+
+    /// Add normalized filter weights around a source position.
+    ///
+    /// * `taps` Taps to add weights to.
+    /// * `center` Source position.
+    /// * `fscale` Scale of filter (for downsampling).
+    /// * `last` Last source index (edge pixels are repeated).
+    fn weights(self, taps: &mut Taps, center: f32, fscale: f32, last: usize) {
+        let support = self.support() * fscale;
+        let left = (center - support).ceil() as i64;
+        let right = (center + support).floor() as i64;
+        let start = taps.weights.len();
+        for j in left..=right {
+            let w = self.kernel((j as f32 - center) / fscale);
+            if w != 0.0 {
+                let j = j.max(0).min(last as i64) as usize;
+                match taps.weights[start..].last_mut() {
+                    Some((k, v)) if *k == j => *v += w,
+                    _ => taps.weights.push((j, w)),
+                }
+            }
+        }
+        let weights = &mut taps.weights[start..];
+        let sum: f32 = weights.iter().map(|(_, w)| w).sum();
+        if sum != 0.0 {
+            weights.iter_mut().for_each(|(_, w)| *w /= sum);
+        }
+        taps.taps.push(Tap::Weights(start, taps.weights.len()));
+    }
+
+    /// Get filter weights around a point along one axis.
+    ///
+    /// * `pos` Source position (pixel centers are at `0.5` offsets).
+    /// * `len` Number of source pixels.
+    ///
+    /// Pixels outside of the source are transparent, so edges are
+    /// antialiased.  With `Nearest`, edge pixels are weighted by coverage.
+    fn point_weights(self, pos: f32, len: usize) -> PointWeights {
+        let mut pw = PointWeights {
+            first: 0,
+            len: 0,
+            weights: [0.0; POINT_TAPS],
+        };
+        if let Filter::Nearest = self {
+            let cov = (pos.min(len as f32 - pos) + 0.5).clamp(0.0, 1.0);
+            if cov > 0.0 {
+                pw.first = (pos.max(0.0) as usize).min(len - 1);
+                pw.len = 1;
+                pw.weights[0] = cov;
+            }
+            return pw;
+        }
+        let center = pos - 0.5;
+        let left = (center - self.support()).ceil() as i64;
+        let right = (center + self.support()).floor() as i64;
+        let weight = |j: i64| self.kernel(j as f32 - center);
+        let sum: f32 = (left..=right).map(weight).sum();
+        let first = left.max(0);
+        let last = right.min(len as i64 - 1);
+        if first <= last {
+            pw.first = first as usize;
+            pw.len = ((last - first + 1) as usize).min(POINT_TAPS);
+            for (i, w) in pw.weights[..pw.len].iter_mut().enumerate() {
+                *w = weight(first + i as i64) / sum;
+            }
+        }
+        pw
+    }
+
+    /// Sample a raster at a point.
+    ///
+    /// * `src` Source raster.
+    /// * `x` Source X position.
+    /// * `y` Source Y position.
+    pub(crate) fn sample_point<F>(
+        self,
+        src: &Raster<F>,
+        x: f32,
+        y: f32,
+    ) -> Pixel
+    where
+        F: Format,
+        Ch32: From<F::Chan>,
+    {
+        let mut p = [0.0; 4];
+        let (sw, sh) = (src.width() as usize, src.height() as usize);
+        if sw == 0 || sh == 0 {
+            return p;
+        }
+        let wx = self.point_weights(x, sw);
+        if wx.len == 0 {
+            return p;
+        }
+        let pixels = src.as_slice();
+        for (j, wy) in self.point_weights(y, sh).iter() {
+            let row = &pixels[j * sw..(j + 1) * sw];
+            for (i, wx) in wx.iter() {
+                let s = from_format(row[i]);
+                for c in 0..4 {
+                    p[c] += s[c] * wx * wy;
+                }
+            }
+        }
+        p
+    }
+}
+
+/// Evaluate a Mitchell-Netravali cubic filter.
+///
+/// * `b` B parameter.
+/// * `c` C parameter.
+/// * `x` Absolute distance from center.
+fn cubic(b: f32, c: f32, x: f32) -> f32 {
+    let x2 = x * x;
+    let x3 = x2 * x;
+    let v = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x3
+            + (-18.0 + 12.0 * b + 6.0 * c) * x2
+            + (6.0 - 2.0 * b)
+    } else if x < 2.0 {
+        (-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    v / 6.0
+}
+
+/// Normalized sinc function
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * PI;
+        x.sin() / x
+    }
+}
+
+/// Resize a raster, with separable filter passes.
+///
+/// Resampling is done with associated alpha and linear gamma, using 32-bit
+/// floating point channels.
+///
+/// * `src` Source raster.
+/// * `width` Width of resized raster.
+/// * `height` Height of resized raster.
+/// * `filter` Resampling filter.
+pub fn resize<F>(
+    src: &Raster<F>,
+    width: u32,
+    height: u32,
+    filter: Filter,
+) -> Raster<F>
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    let sw = src.width() as usize;
+    let sh = src.height() as usize;
+    let (w, h) = (width as usize, height as usize);
+    if sw == 0 || sh == 0 || w == 0 || h == 0 {
+        return RasterBuilder::new().with_clear(width, height);
+    }
+    let xtaps = filter.taps(sw, w);
+    let ytaps = filter.taps(sh, h);
+    resample(src, &xtaps, &ytaps)
+}
+
+/// Shift a raster by a fraction of a pixel, with bilinear filtering.
+///
+/// * `src` Source raster.
+/// * `fx` Fraction of pixel to shift right (`0.0` to `1.0`).
+/// * `fy` Fraction of pixel to shift down (`0.0` to `1.0`).
+///
+/// The shifted raster is one pixel larger on each shifted axis, with
+/// transparent edges.
+pub(crate) fn shift<F>(src: &Raster<F>, fx: f32, fy: f32) -> Raster<F>
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    let xtaps = shift_taps(src.width() as usize, fx);
+    let ytaps = shift_taps(src.height() as usize, fy);
+    resample(src, &xtaps, &ytaps)
+}
+
+/// Get taps to shift pixels along one axis.
+///
+/// * `len` Number of source pixels.
+/// * `frac` Fraction of pixel to shift.
+fn shift_taps(len: usize, frac: f32) -> Taps {
+    let mut taps = Taps::default();
+    if frac <= 0.0 {
+        (0..len).for_each(|i| taps.push_weights(Some((i, 1.0))));
+        return taps;
+    }
+    for i in 0..=len {
+        let w = |j| if j < i { frac } else { 1.0 - frac };
+        let js = i.saturating_sub(1)..(i + 1).min(len);
+        taps.push_weights(js.map(|j| (j, w(j))));
+    }
+    taps
+}
+
+/// Resample a raster with separable filter passes.
+///
+/// * `src` Source raster.
+/// * `xtaps` Source taps for each destination column.
+/// * `ytaps` Source taps for each destination row.
+fn resample<F>(src: &Raster<F>, xtaps: &Taps, ytaps: &Taps) -> Raster<F>
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    let sw = src.width() as usize;
+    let (w, h) = (xtaps.len(), ytaps.len());
+    if sw == 0 || src.height() == 0 {
+        return RasterBuilder::new().with_clear(w as u32, h as u32);
+    }
+    let pixels: Vec<Pixel> =
+        src.as_slice().iter().map(|p| from_format(*p)).collect();
+    // Horizontal pass
+    let mut tmp = Vec::with_capacity(w * src.height() as usize);
+    for row in pixels.chunks(sw) {
+        tmp.extend((0..w).map(|i| xtaps.sample(i, |j| row[j])));
+    }
+    // Vertical pass
+    let mut pixels = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let p = ytaps.sample(y, |j| tmp[j * w + x]);
+            pixels.push(to_format::<F>(p));
+        }
+    }
+    RasterBuilder::new().with_pixels(w as u32, h as u32, pixels)
+}
+
+/// Convert a pixel to associated, linear channels.
+pub(crate) fn from_format<F>(p: F) -> Pixel
+where
+    F: Format,
+    Ch32: From<F::Chan>,
+{
+    let p: AssocLRgba32 = p.convert();
+    let [r, g, b, a] = p.rgba();
+    [r.into(), g.into(), b.into(), a.into()]
+}
+
+/// Convert an associated, linear pixel to a format.
+///
+/// Filters with negative lobes can overshoot, so channels are clamped.
+pub(crate) fn to_format<F>(p: Pixel) -> F
+where
+    F: Format,
+    F::Chan: From<Ch32>,
+    Ch32: From<F::Chan>,
+{
+    let a = Ch32::new(p[3]);
+    let c = |v: f32| Ch32::new(v).min(a);
+    AssocLRgba32::with_rgba([c(p[0]), c(p[1]), c(p[2]), a]).convert()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pix::*;
+
+    #[test]
+    fn nearest() {
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(2, 1);
+        r.set_pixel(1, 0, SepLGray8::new(0xFF));
+        let r = resize(&r, 4, 2, Filter::Nearest);
+        #[rustfmt::skip]
+        let v = [
+            0x00, 0x00, 0xFF, 0xFF,
+            0x00, 0x00, 0xFF, 0xFF,
+        ];
+        assert_eq!(r.as_u8_slice(), &v[..]);
+    }
+
+    #[test]
+    fn bilinear() {
+        let mut r = RasterBuilder::<SepLGray8>::new().with_clear(2, 1);
+        r.set_pixel(1, 0, SepLGray8::new(0xFF));
+        let r = resize(&r, 4, 1, Filter::Bilinear);
+        assert_eq!(r.as_u8_slice(), &[0x00, 0x40, 0xBF, 0xFF]);
+        let r = resize(&r, 2, 1, Filter::Bilinear);
+        assert_eq!(r.as_u8_slice(), &[0x30, 0xCF]);
+    }
+
+    #[test]
+    fn same_size() {
+        let mut r = RasterBuilder::<AssocSRgba8>::new().with_clear(3, 3);
+        r.set_pixel(1, 1, AssocSRgba8::with_alpha(0x40, 0x80, 0x60, 0x80));
+        r.set_pixel(2, 0, AssocSRgba8::new(0xFF, 0x80, 0x00));
+        let filters = [
+            Filter::Nearest,
+            Filter::Bilinear,
+            Filter::CatmullRom,
+            Filter::Lanczos3,
+        ];
+        for filter in filters.iter() {
+            let s = resize(&r, 3, 3, *filter);
+            for (p, q) in s.as_u8_slice().iter().zip(r.as_u8_slice()) {
+                assert!((i32::from(*p) - i32::from(*q)).abs() <= 1);
+            }
+        }
+    }
+    const FILTERS: [Filter; 5] = [
+        Filter::Nearest,
+        Filter::Bilinear,
+        Filter::CatmullRom,
+        Filter::Mitchell,
+        Filter::Lanczos3,
+    ];
+
+    #[test]
+    fn downscale_weights() {
+        for filter in FILTERS.iter() {
+            for (src, dst) in [(2, 1), (7, 3), (64, 5), (100, 99)].iter() {
+                let taps = filter.taps(*src, *dst);
+                assert_eq!(taps.len(), *dst);
+                for tap in taps.taps.iter() {
+                    if let Tap::Weights(start, end) = *tap {
+                        let weights = &taps.weights[start..end];
+                        assert!(weights.iter().all(|(j, _)| *j < *src));
+                        let sum: f32 = weights.iter().map(|(_, w)| w).sum();
+                        assert!((sum - 1.0).abs() < 1e-5, "{:?}", filter);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn downscale_constant() {
+        let clr = AssocSRgba8::with_alpha(0x40, 0x80, 0x60, 0xC0);
+        let r = RasterBuilder::<AssocSRgba8>::new().with_color(17, 13, clr);
+        for filter in FILTERS.iter() {
+            for (w, h) in [(8, 6), (5, 4), (1, 1)].iter() {
+                let s = resize(&r, *w, *h, *filter);
+                assert_eq!((s.width(), s.height()), (*w, *h));
+                let v = [0x40, 0x80, 0x60, 0xC0];
+                for (c, d) in s.as_u8_slice().iter().zip(v.iter().cycle()) {
+                    assert!((i32::from(*c) - d).abs() <= 1, "{:?}", filter);
+                }
+            }
+        }
+    }
+}
@@ -3,26 +3,80 @@
 // Copyright (c) 2019  Douglas P Lau
 // Copyright (c) 2020  Jeron Aldaron Lau
 //
-use crate::Blend;
+use crate::blend::{
+    blend_over, mul, scale, separate, Blend, BlendMode, PorterDuff,
+    Rounding,
+};
+use crate::lerp::{Lerp, LerpAlpha};
 use pix::{
-    Alpha, AlphaMode, AssocRgb, Channel, Format, GammaMode, Rgb, Translucent,
+    Alpha, AlphaMode, AssocRgb, Ch32, Channel, Format, GammaMode, Rgb,
+    SepRgb, Translucent,
 };
+use std::mem::size_of;
 
 impl<C, A, M, G> Blend for Rgb<C, A, M, G>
 where
-    C: Channel,
-    A: Alpha<Chan = C>,
+    C: Channel + Lerp,
+    A: Alpha<Chan = C> + LerpAlpha,
     A: From<C>,
     M: AlphaMode,
     G: GammaMode,
 {
+    /// Blend pixels with `over` operation.
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_slice<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            feature = "simd"
+        ))]
+        let n = crate::rgba::over_slice(dst, src, clr, opacity, rnd);
+        #[cfg(all(target_arch = "aarch64", feature = "simd"))]
+        let n = crate::neon::over_slice(dst, src, clr, opacity, rnd);
+        #[cfg(not(all(
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "aarch64"
+            ),
+            feature = "simd"
+        )))]
+        let n = 0;
+        let len = dst.len().min(src.len());
+        if len > n {
+            Self::over_fallback(&mut dst[n..], &src[n..], clr, opacity, rnd);
+        }
+    }
+
     /// Blend pixels with `over` operation (slow fallback).
     ///
     /// * `dst` Destination pixels.
     /// * `src` Source pixels.
     /// * `clr` Mask color.
-    fn over_fallback<B, H>(dst: &mut [Self], src: &[B], clr: Self)
-    where
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        opacity: H,
+        rnd: Rounding,
+    ) where
         B: Format<Chan = H>,
         C: From<H>,
         H: Channel,
@@ -30,42 +84,421 @@ where
     {
         // Over operation requires alpha is Associated, translucency needed for
         // blending.
-        let clr: AssocRgb<H, Translucent<H>, G> = clr.convert();
+        let clr = MaskColor::<H, G>::new(clr, opacity, rnd);
 
         for (bot, top) in dst.iter_mut().zip(src) {
             // Apply mask color to source raster.
+            let src = clr.apply(*top);
+
+            // Over Operation
+            *bot = over_pixel(*bot, src, rnd);
+        }
+    }
+
+    /// Blend pixels with `over` operation, clipped by a mask (slow
+    /// fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `mask` Mask pixels (alpha is coverage of source).
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn over_masked_fallback<B, N, H>(
+        dst: &mut [Self],
+        src: &[B],
+        mask: &[N],
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        N: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        for ((bot, top), m) in dst.iter_mut().zip(src).zip(mask) {
+            // Scale source raster by mask coverage.
             let src: AssocRgb<H, Translucent<H>, G> = top.convert();
-            let src = clr * src;
+            let m = scale(m.rgba()[3], opacity, rnd);
+            let [r, g, b, a] = src.rgba();
+            let src = AssocRgb::<H, Translucent<H>, G>::with_rgba([
+                mul(r, m, rnd),
+                mul(g, m, rnd),
+                mul(b, m, rnd),
+                mul(a, m, rnd),
+            ]);
 
             // Over Operation
-            *bot = Self::over(*bot, src);
+            *bot = over_pixel(*bot, src, rnd);
         }
     }
 
     /// Blend pixel on top of another, using "over".
-    fn over<B, H>(dst: Self, src: B) -> Self
+    fn over<B, H>(dst: Self, src: B, rnd: Rounding) -> Self
     where
         B: Format<Chan = H>,
         C: From<H>,
         H: Channel,
         H: From<C>,
+    {
+        let src: AssocRgb<H, Translucent<H>, G> = src.convert();
+
+        over_pixel(dst, src, rnd)
+    }
+
+    /// Composite pixels with a Porter-Duff operation (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `op` Compositing operation.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn composite_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        op: PorterDuff,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        let clr = MaskColor::<H, G>::new(clr, opacity, rnd);
+
+        for (bot, top) in dst.iter_mut().zip(src) {
+            // Apply mask color to source raster.
+            let src = clr.apply(*top);
+
+            *bot = composite_pixel(*bot, src, op, rnd);
+        }
+    }
+
+    /// Composite one pixel with another, using a Porter-Duff operation.
+    fn composite<B, H>(
+        dst: Self,
+        src: B,
+        op: PorterDuff,
+        rnd: Rounding,
+    ) -> Self
+    where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+    {
+        let src: AssocRgb<H, Translucent<H>, G> = src.convert();
+
+        composite_pixel(dst, src, op, rnd)
+    }
+
+    /// Blend pixels with a blend mode (slow fallback).
+    ///
+    /// * `dst` Destination pixels.
+    /// * `src` Source pixels.
+    /// * `clr` Mask color.
+    /// * `mode` Blend mode.
+    /// * `opacity` Opacity of source (`MAX` is opaque).
+    /// * `rnd` Rounding policy.
+    fn blend_fallback<B, H>(
+        dst: &mut [Self],
+        src: &[B],
+        clr: Self,
+        mode: BlendMode,
+        opacity: H,
+        rnd: Rounding,
+    ) where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+        H: From<Ch32>,
+        Ch32: From<H>,
+    {
+        let clr = MaskColor::<H, G>::new(clr, opacity, rnd);
+
+        for (bot, top) in dst.iter_mut().zip(src) {
+            // Apply mask color to source raster.
+            let src = clr.apply(*top);
+
+            *bot = Self::blend(*bot, src, mode);
+        }
+    }
+
+    /// Blend pixel on top of another, using a blend mode.
+    ///
+    /// Blending is done with 32-bit floating point channels.
+    fn blend<B, H>(dst: Self, src: B, mode: BlendMode) -> Self
+    where
+        B: Format<Chan = H>,
+        C: From<H>,
+        H: Channel,
+        H: From<C>,
+        H: From<Ch32>,
+        Ch32: From<H>,
     {
         let dst: AssocRgb<H, Translucent<H>, G> = dst.convert();
         let src: AssocRgb<H, Translucent<H>, G> = src.convert();
+        let dst: AssocRgb<Ch32, Translucent<Ch32>, G> = dst.convert();
+        let src: AssocRgb<Ch32, Translucent<Ch32>, G> = src.convert();
+
+        let sa = f32::from(src.alpha().value());
+        let da = f32::from(dst.alpha().value());
+        let a = sa + da - sa * da;
+        let sc = [
+            f32::from(src.red()),
+            f32::from(src.green()),
+            f32::from(src.blue()),
+        ];
+        let dc = [
+            f32::from(dst.red()),
+            f32::from(dst.green()),
+            f32::from(dst.blue()),
+        ];
+        let (r, g, b) = if mode.is_separable() {
+            (
+                mode.blend_channel(sc[0], sa, dc[0], da),
+                mode.blend_channel(sc[1], sa, dc[1], da),
+                mode.blend_channel(sc[2], sa, dc[2], da),
+            )
+        } else {
+            // Non-separable modes need the whole (separated) RGB triplet
+            let cs = [
+                separate(sc[0], sa),
+                separate(sc[1], sa),
+                separate(sc[2], sa),
+            ];
+            let cb = [
+                separate(dc[0], da),
+                separate(dc[1], da),
+                separate(dc[2], da),
+            ];
+            let bl = blend_non_separable(mode, cb, cs);
+            (
+                blend_over(bl[0], sc[0], sa, dc[0], da),
+                blend_over(bl[1], sc[1], sa, dc[1], da),
+                blend_over(bl[2], sc[2], sa, dc[2], da),
+            )
+        };
+
+        let p = AssocRgb::<Ch32, Translucent<Ch32>, G>::with_rgba([
+            Ch32::new(r),
+            Ch32::new(g),
+            Ch32::new(b),
+            Ch32::new(a),
+        ]);
+        let p: AssocRgb<H, Translucent<H>, G> = p.convert();
+        p.convert()
+    }
+
+    /// Interpolate one pixel linearly with another.
+    ///
+    /// Interpolation is done with associated alpha, so colors of translucent
+    /// pixels are weighted by alpha.
+    fn lerp(a: Self, b: Self, t: C) -> Self {
+        let a: AssocRgb<C, Translucent<C>, G> = a.convert();
+        let b: AssocRgb<C, Translucent<C>, G> = b.convert();
+
+        AssocRgb::<C, Translucent<C>, G>::with_alpha(
+            a.red().lerp(b.red(), t),
+            a.green().lerp(b.green(), t),
+            a.blue().lerp(b.blue(), t),
+            LerpAlpha::lerp(a.alpha(), b.alpha(), t),
+        )
+        .convert()
+    }
+}
+
+/// Blend pixel on top of another, using "over".
+///
+/// * `dst` Destination pixel.
+/// * `src` Associated source pixel.
+/// * `rnd` Rounding policy.
+fn over_pixel<F, H, G>(
+    dst: F,
+    src: AssocRgb<H, Translucent<H>, G>,
+    rnd: Rounding,
+) -> F
+where
+    F: Format,
+    F::Chan: From<H>,
+    H: Channel + From<F::Chan>,
+    G: GammaMode,
+{
+    let dst: AssocRgb<H, Translucent<H>, G> = dst.convert();
+
+    let one_minus_src_a = H::MAX - src.alpha().value();
+    let a = src.alpha().value()
+        + mul(dst.alpha().value(), one_minus_src_a, rnd);
+    let r = src.red() + mul(dst.red(), one_minus_src_a, rnd);
+    let g = src.green() + mul(dst.green(), one_minus_src_a, rnd);
+    let b = src.blue() + mul(dst.blue(), one_minus_src_a, rnd);
+
+    AssocRgb::<F::Chan, Translucent<F::Chan>, G>::with_alpha(r, g, b, a)
+        .convert()
+}
+
+/// Composite one pixel with another, using a Porter-Duff operation.
+///
+/// * `dst` Destination pixel.
+/// * `src` Associated source pixel.
+/// * `op` Compositing operation.
+/// * `rnd` Rounding policy.
+fn composite_pixel<F, H, G>(
+    dst: F,
+    src: AssocRgb<H, Translucent<H>, G>,
+    op: PorterDuff,
+    rnd: Rounding,
+) -> F
+where
+    F: Format,
+    F::Chan: From<H>,
+    H: Channel + From<F::Chan>,
+    G: GammaMode,
+{
+    let dst: AssocRgb<H, Translucent<H>, G> = dst.convert();
+
+    let (sa, da) = (src.alpha().value(), dst.alpha().value());
+    let (fa, fb) = op.factors(sa, da);
+    let a = scale(sa, fa, rnd) + scale(da, fb, rnd);
+    let r = scale(src.red(), fa, rnd) + scale(dst.red(), fb, rnd);
+    let g = scale(src.green(), fa, rnd) + scale(dst.green(), fb, rnd);
+    let b = scale(src.blue(), fa, rnd) + scale(dst.blue(), fb, rnd);
+
+    AssocRgb::<F::Chan, Translucent<F::Chan>, G>::with_alpha(r, g, b, a)
+        .convert()
+}
+
+/// Mask color, with opacity applied
+enum MaskColor<H: Channel, G: GammaMode> {
+    /// Separated color, for `Fast` rounding
+    Fast(SepRgb<H, Translucent<H>, G>),
+    /// Associated color, for `Exact` rounding
+    Exact(AssocRgb<H, Translucent<H>, G>),
+}
+
+impl<H: Channel, G: GammaMode> MaskColor<H, G> {
+    /// Create a new mask color.
+    ///
+    /// Opacity is folded into the color, so it costs nothing per pixel.
+    /// Float channels are not affected by the rounding policy.
+    fn new<F>(clr: F, opacity: H, rnd: Rounding) -> Self
+    where
+        F: Format,
+        H: From<F::Chan>,
+    {
+        let clr: AssocRgb<H, Translucent<H>, G> = clr.convert();
+        let s = |c| scale(c, opacity, rnd);
+        if rnd == Rounding::Exact && size_of::<H>() < 4 {
+            let [r, g, b, a] = clr.rgba();
+            MaskColor::Exact(AssocRgb::with_rgba([s(r), s(g), s(b), s(a)]))
+        } else {
+            // Opacity scales alpha only, leaving the separated color as-is
+            let [r, g, b, a] = SepRgb::from(clr).rgba();
+            MaskColor::Fast(SepRgb::with_rgba([r, g, b, s(a)]))
+        }
+    }
+
+    /// Apply the mask color to a source pixel.
+    ///
+    /// With `Fast` rounding, pixels are multiplied by separating alpha,
+    /// multiplying each channel and associating the product.  With `Exact`
+    /// rounding, associated channels are multiplied directly, and products
+    /// are rounded to nearest.  Converted mask pixels can have channels
+    /// above alpha, so those are clamped, as separating would saturate.
+    fn apply<B>(&self, src: B) -> AssocRgb<H, Translucent<H>, G>
+    where
+        B: Format<Chan = H>,
+    {
+        let src: AssocRgb<H, Translucent<H>, G> = src.convert();
+        match self {
+            MaskColor::Fast(clr) => (*clr * SepRgb::from(src)).into(),
+            MaskColor::Exact(clr) => {
+                let sa = src.alpha().value();
+                let m = |c, s: H| mul(c, s.min(sa), Rounding::Exact);
+                AssocRgb::with_rgba([
+                    m(clr.red(), src.red()),
+                    m(clr.green(), src.green()),
+                    m(clr.blue(), src.blue()),
+                    m(clr.alpha().value(), src.alpha().value()),
+                ])
+            }
+        }
+    }
+}
+
+/// Blend RGB triplets with a non-separable blend mode.
+///
+/// * `mode` Blend mode (`Hue`, `Saturation`, `Color` or `Luminosity`).
+/// * `cb` Backdrop (destination) color.
+/// * `cs` Source color.
+fn blend_non_separable(
+    mode: BlendMode,
+    cb: [f32; 3],
+    cs: [f32; 3],
+) -> [f32; 3] {
+    match mode {
+        BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+        BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+        BlendMode::Color => set_lum(cs, lum(cb)),
+        BlendMode::Luminosity => set_lum(cb, lum(cs)),
+        _ => unreachable!("separable blend mode"),
+    }
+}
+
+/// Get the luminosity of a color.
+fn lum(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+/// Set the luminosity of a color.
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(c);
+    clip_color([c[0] + d, c[1] + d, c[2] + d])
+}
+
+/// Clip a color into range, preserving luminosity.
+fn clip_color(c: [f32; 3]) -> [f32; 3] {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut c = c;
+    if n < 0.0 {
+        for v in c.iter_mut() {
+            *v = l + (*v - l) * l / (l - n);
+        }
+    }
+    if x > 1.0 {
+        for v in c.iter_mut() {
+            *v = l + (*v - l) * (1.0 - l) / (x - l);
+        }
+    }
+    c
+}
 
-        let one_minus_src_a = H::MAX - src.alpha().value();
-        let a = src.alpha().value() + dst.alpha().value() * one_minus_src_a;
-        let r = src.red() + dst.red() * one_minus_src_a;
-        let g = src.green() + dst.green() * one_minus_src_a;
-        let b = src.blue() + dst.blue() * one_minus_src_a;
+/// Get the saturation of a color.
+fn sat(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
 
-        AssocRgb::<C, Translucent<C>, G>::with_alpha(r, g, b, a).convert()
+/// Set the saturation of a color.
+fn set_sat(c: [f32; 3], s: f32) -> [f32; 3] {
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    if x > n {
+        let k = s / (x - n);
+        [(c[0] - n) * k, (c[1] - n) * k, (c[2] - n) * k]
+    } else {
+        [0.0; 3]
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Blend;
+    use super::{Blend, Rounding};
 
     #[test]
     fn rgba8_transparent() {
@@ -73,8 +506,8 @@ mod tests {
         let t = pix::AssocSRgba8::with_alpha(0x00, 0x00, 0x00, 0x00);
         let p = pix::AssocSRgba8::with_alpha(20, 40, 80, 160);
 
-        let r1 = Blend::over(t, p);
-        let r2 = Blend::over(p, t);
+        let r1 = Blend::over(t, p, Rounding::Fast);
+        let r2 = Blend::over(p, t, Rounding::Fast);
 
         assert_eq!(r1, p);
         assert_eq!(r1, r2);
@@ -85,8 +518,95 @@ mod tests {
         let t = pix::AssocSRgba8::with_alpha(0x00, 0x00, 0x00, 0x00);
         let p = pix::AssocSRgba8::new(0xFF, 0xFF, 0xFF);
 
-        let r = Blend::over(p, t);
+        let r = Blend::over(p, t, Rounding::Fast);
 
         assert_eq!(r, p);
     }
+
+    #[test]
+    fn porter_duff() {
+        use crate::PorterDuff::*;
+        let d = pix::AssocSRgba8::with_alpha(0x40, 0x20, 0x10, 0x80);
+        let s = pix::AssocSRgba8::with_alpha(0x10, 0x20, 0x40, 0x40);
+        let t = pix::AssocSRgba8::with_alpha(0x00, 0x00, 0x00, 0x00);
+        let r = Rounding::Fast;
+
+        assert_eq!(Blend::composite(d, s, Clear, r), t);
+        assert_eq!(Blend::composite(d, s, Src, r), s);
+        assert_eq!(Blend::composite(d, s, Dst, r), d);
+        assert_eq!(Blend::composite(d, t, SrcIn, r), t);
+        assert_eq!(Blend::composite(d, t, DstOut, r), d);
+        assert_eq!(Blend::composite(d, s, DstOver, r), Blend::over(s, d, r));
+        assert_eq!(
+            Blend::composite(d, s, SrcIn, r),
+            pix::AssocSRgba8::with_alpha(0x08, 0x10, 0x20, 0x20)
+        );
+        assert_eq!(
+            Blend::composite(d, s, Xor, r),
+            pix::AssocSRgba8::with_alpha(0x37, 0x27, 0x2B, 0x7F)
+        );
+    }
+
+    #[test]
+    fn blend_modes() {
+        use crate::BlendMode::*;
+        let d = pix::AssocSRgba8::new(0x80, 0x40, 0xFF);
+        let s = pix::AssocSRgba8::new(0xFF, 0x80, 0x00);
+        let t = pix::AssocSRgba8::with_alpha(0x00, 0x00, 0x00, 0x00);
+
+        assert_eq!(Blend::blend(d, s, Normal), s);
+        assert_eq!(Blend::blend(d, t, Multiply), d);
+        assert_eq!(
+            Blend::blend(d, s, Multiply),
+            pix::AssocSRgba8::new(0x80, 0x20, 0x00)
+        );
+        assert_eq!(
+            Blend::blend(d, s, Screen),
+            pix::AssocSRgba8::new(0xFF, 0xA0, 0xFF)
+        );
+        assert_eq!(
+            Blend::blend(d, s, Darken),
+            pix::AssocSRgba8::new(0x80, 0x40, 0x00)
+        );
+        assert_eq!(
+            Blend::blend(d, s, Difference),
+            pix::AssocSRgba8::new(0x7F, 0x40, 0xFF)
+        );
+    }
+
+    #[test]
+    fn non_separable() {
+        use crate::BlendMode::*;
+        let d = pix::AssocSRgba8::new(0x80, 0x80, 0x80);
+        let s = pix::AssocSRgba8::new(0xFF, 0x00, 0x00);
+
+        assert_eq!(Blend::blend(d, s, Saturation), d);
+        assert_eq!(
+            Blend::blend(s, d, Hue),
+            pix::AssocSRgba8::new(0x4D, 0x4D, 0x4D)
+        );
+        assert_eq!(
+            Blend::blend(s, d, Luminosity),
+            pix::AssocSRgba8::new(0xFF, 0x4A, 0x4A)
+        );
+        assert_eq!(
+            Blend::blend(d, s, Color),
+            Blend::blend(s, d, Luminosity)
+        );
+    }
+
+    #[test]
+    fn cross_fade() {
+        let a = pix::AssocSRgba8::with_alpha(0x00, 0x40, 0x80, 0x80);
+        let b = pix::AssocSRgba8::with_alpha(0x80, 0x40, 0x00, 0xFF);
+        let t = pix::Ch8::new(0x80);
+
+        assert_eq!(Blend::lerp(a, b, pix::Ch8::new(0)), a);
+        assert_eq!(Blend::lerp(a, b, pix::Ch8::new(0xFF)), b);
+        let c = pix::AssocSRgba8::with_alpha(0x40, 0x40, 0x40, 0xC0);
+        assert_eq!(Blend::lerp(a, b, t), c);
+        let mut dst = [a, b];
+        pix::AssocSRgba8::lerp_slice(&mut dst, &[b, b], t);
+        assert_eq!(dst, [c, b]);
+    }
 }
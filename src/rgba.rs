@@ -1,131 +1,410 @@
-// rgb.rs       Rgb pixel format.
+// rgba.rs      SIMD compositing for 8-bit RGBA pixels.
 //
 // Copyright (c) 2018-2019  Douglas P Lau
 //
-use pix::{Mask8, Rgb, Translucent, Associated};
-use crate::Blend;
+use crate::blend::{bits, scale, separate_mask, Rounding};
+use pix::{AlphaMode, AlphaModeID, Ch8, Format, GammaMode, GammaModeID};
+use std::mem::size_of;
 
-#[cfg(all(target_arch = "x86", feature = "simd"))]
+#[cfg(target_arch = "x86")]
 use std::arch::x86::*;
-#[cfg(all(target_arch = "x86_64", feature = "simd"))]
+#[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
-impl Blend for Rgb<Ch8, Translucent<Ch8>, Associated> {
-    /// Blend pixels with an alpha mask.
-    ///
-    /// * `dst` Destination pixels.
-    /// * `mask` Alpha mask for compositing.
-    /// * `src` Source color.
-    fn mask_over(dst: &mut [Self], mask: &[u8], clr: Self) {
-        #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"),
-              feature = "simd"))]
-        {
-            if is_x86_feature_detected!("ssse3") {
-                let len = dst.len().min(mask.len());
-                if len >= 4 {
-                    unsafe { over_x86(dst, mask, clr) }
-                }
-                let ln = (len >> 2) << 2;
-                if len > ln {
-                    Self::mask_over_fallback(&mut dst[ln..], &mask[ln..], clr);
-                }
-                return;
-            }
-        }
-        Blend::mask_over_fallback(dst, mask, clr);
+/// Check if formats are associated 8-bit RGBA over an 8-bit alpha mask.
+///
+/// `F` must be an `Rgb` format -- with 8-bit channels and 4 bytes, it can
+/// only be `Rgb<Ch8, Translucent<Ch8>, AssociatedAlpha, G>`.  The only format
+/// with unknown alpha and gamma modes is `Mask`.
+fn is_rgba8_over_mask8<F: Format, B: Format>() -> bool {
+    <F as AlphaMode>::ID == AlphaModeID::Associated
+        && size_of::<F>() == 4
+        && size_of::<F::Chan>() == 1
+        && <B as AlphaMode>::ID == AlphaModeID::UnknownAlpha
+        && <B as GammaMode>::ID == GammaModeID::UnknownGamma
+        && size_of::<B>() == 1
+}
+
+/// Get the mask color constants for the SIMD kernels.
+///
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// Returns color channels (packed), and alpha scaled by opacity.  With exact
+/// rounding, the color is associated and scaled by opacity, so it can be
+/// multiplied directly with the mask.  Otherwise, it is separated from alpha.
+fn mask_color<F: Format>(clr: F, opacity: Ch8, rnd: Rounding) -> (i32, i32) {
+    debug_assert_eq!(size_of::<F>(), 4);
+    let rgba: [u8; 4] = bits(clr);
+    let a = Ch8::new(rgba[3]);
+    let k = |c| {
+        let c = if rnd == Rounding::Exact {
+            scale(Ch8::new(c), opacity, rnd)
+        } else {
+            separate_mask(Ch8::new(c), a, rnd)
+        };
+        i32::from(u8::from(c))
+    };
+    let k = k(rgba[0]) | k(rgba[1]) << 8 | k(rgba[2]) << 16;
+    (k, i32::from(u8::from(scale(a, opacity, rnd))))
+}
+
+/// Blend associated 8-bit RGBA pixels with an 8-bit alpha mask, using SIMD.
+///
+/// 16-bit and float pixels are blended by `deep::over_rgba_slice`.  Other
+/// formats are ignored.  Any pixels remaining at the end of the slices
+/// must be blended by the caller.
+///
+/// * `dst` Destination pixels (must be an `Rgb` format).
+/// * `src` Source mask pixels.
+/// * `clr` Mask color.
+/// * `opacity` Opacity of source.
+/// * `rnd` Rounding policy.
+///
+/// Returns the number of pixels blended.
+pub(crate) fn over_slice<F, B>(
+    dst: &mut [F],
+    src: &[B],
+    clr: F,
+    opacity: B::Chan,
+    rnd: Rounding,
+) -> usize
+where
+    F: Format,
+    B: Format,
+{
+    if !is_rgba8_over_mask8::<F, B>() {
+        return crate::deep::over_rgba_slice(dst, src, clr, opacity, rnd);
+    }
+    let len = dst.len().min(src.len());
+    let opacity = unsafe { std::mem::transmute_copy(&opacity) };
+    let (k, a) = mask_color(clr, opacity, rnd);
+    let exact = rnd == Rounding::Exact;
+    let pix = dst.as_mut_ptr() as *mut u8;
+    let mask = src.as_ptr() as *const u8;
+    if is_x86_feature_detected!("avx2") {
+        unsafe { over_avx2(pix, mask, len, k, a, exact) }
+    } else if is_x86_feature_detected!("ssse3") {
+        unsafe { over_ssse3(pix, mask, len, k, a, exact) }
+    } else {
+        0
     }
+}
 
-    /// Blend pixels with an alpha mask (slow fallback).
-    ///
-    /// * `dst` Destination pixels.
-    /// * `mask` Alpha mask for compositing.
-    /// * `src` Source color.
-    fn mask_over_fallback(dst: &mut [Self], mask: &[u8], src: Self) {
-        for (bot, m) in dst.iter_mut().zip(mask) {
-            *bot = src.with_alpha_over(*bot, *m);
-        }
+/// Composite a color with a mask (AVX2).
+///
+/// * `exact` Round products to nearest.
+///
+/// Returns the number of pixels blended (a multiple of 4).
+#[target_feature(enable = "avx2")]
+unsafe fn over_avx2(
+    pix: *mut u8,
+    mask: *const u8,
+    len: usize,
+    k: i32,
+    a: i32,
+    exact: bool,
+) -> usize {
+    let kv = _mm256_set1_epi32(k);
+    let ca = _mm256_set1_epi8(a as i8);
+    // Truncate len to multiple of 8
+    let len8 = (len >> 3) << 3;
+    // 8 pixels at a time
+    for i in (0..len8).step_by(8) {
+        let dst = pix.add(i * 4) as *mut __m256i;
+        let src = mask.add(i) as *const i64;
+        // get 8 alpha values from src,
+        // then shuffle each lane: 76543210 => 3333222211110000 (low)
+        //                                  => 7777666655554444 (high)
+        let m = _mm256_set1_epi64x(std::ptr::read_unaligned(src));
+        let m = _mm256_shuffle_epi8(
+            m,
+            _mm256_set_epi8(
+                7, 7, 7, 7, 6, 6, 6, 6, 5, 5, 5, 5, 4, 4, 4, 4, 3, 3, 3, 3, 2,
+                2, 2, 2, 1, 1, 1, 1, 0, 0, 0, 0,
+            ),
+        );
+        // get RGBA values from dst
+        let bot = _mm256_loadu_si256(dst);
+        // compose top over bot
+        let out = over_u8x32_avx2(kv, ca, m, bot, exact);
+        // store blended pixels
+        _mm256_storeu_si256(dst, out);
+    }
+    if len - len8 >= 4 {
+        let (pix, mask) = (pix.add(len8 * 4), mask.add(len8));
+        len8 + over_ssse3(pix, mask, 4, k, a, exact)
+    } else {
+        len8
     }
 }
 
-/// Composite a color with a mask.
-#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"),
-          feature = "simd"))]
+/// Composite packed mask color over packed pixels (AVX2).
+///
+/// * `k` Mask color, separated from alpha (alpha lanes are ignored).  With
+///   `exact`, this is the associated color instead.
+/// * `ca` Mask color alpha.
+/// * `m` Mask values, for each channel.
+/// * `b` Bottom pixels.
+/// * `exact` Round products to nearest.
+#[target_feature(enable = "avx2")]
+unsafe fn over_u8x32_avx2(
+    k: __m256i,
+    ca: __m256i,
+    m: __m256i,
+    b: __m256i,
+    exact: bool,
+) -> __m256i {
+    let alpha = _mm256_set1_epi32(0xFF00_0000_u32 as i32);
+    // Source alpha: mask alpha * mask value
+    let sa = mul_u8x32_avx2(ca, m, exact);
+    // Source color: separated color * source alpha, or associated color *
+    // mask value when exact
+    let s = mul_u8x32_avx2(k, if exact { m } else { sa }, exact);
+    let s = _mm256_or_si256(
+        _mm256_andnot_si256(alpha, s),
+        _mm256_and_si256(alpha, sa),
+    );
+    // Over: s + b * (1 - sa)
+    let one_minus_sa = _mm256_xor_si256(sa, _mm256_set1_epi8(-1));
+    _mm256_adds_epu8(s, mul_u8x32_avx2(b, one_minus_sa, exact))
+}
+
+/// Multiply packed u8 values as `Ch8` channels (AVX2).
+#[target_feature(enable = "avx2")]
+unsafe fn mul_u8x32_avx2(a: __m256i, b: __m256i, exact: bool) -> __m256i {
+    let zero = _mm256_setzero_si256();
+    let al = _mm256_unpacklo_epi8(a, zero);
+    let bl = _mm256_unpacklo_epi8(b, zero);
+    let ah = _mm256_unpackhi_epi8(a, zero);
+    let bh = _mm256_unpackhi_epi8(b, zero);
+    let (lo, hi) = if exact {
+        (mul_exact_u16x16_avx2(al, bl), mul_exact_u16x16_avx2(ah, bh))
+    } else {
+        (mul_u16x16_avx2(al, bl), mul_u16x16_avx2(ah, bh))
+    };
+    _mm256_packus_epi16(lo, hi)
+}
+
+/// Multiply u8 values in u16 lanes as `Ch8` channels (AVX2).
+#[target_feature(enable = "avx2")]
+unsafe fn mul_u16x16_avx2(a: __m256i, b: __m256i) -> __m256i {
+    // Same as Ch8 multiply: expand each value to 12 bits (v << 4 | v >> 4),
+    // then keep the high 16 bits of the 24-bit product.
+    let a = _mm256_or_si256(_mm256_slli_epi16(a, 4), _mm256_srli_epi16(a, 4));
+    let b = _mm256_or_si256(_mm256_slli_epi16(b, 4), _mm256_srli_epi16(b, 4));
+    _mm256_mulhi_epu16(a, b)
+}
+
+/// Multiply u8 values in u16 lanes, rounding to nearest (AVX2).
+#[target_feature(enable = "avx2")]
+unsafe fn mul_exact_u16x16_avx2(a: __m256i, b: __m256i) -> __m256i {
+    // c = a * b + 128, then (c + (c >> 8)) >> 8
+    let c = _mm256_add_epi16(_mm256_mullo_epi16(a, b), _mm256_set1_epi16(128));
+    _mm256_srli_epi16(_mm256_add_epi16(c, _mm256_srli_epi16(c, 8)), 8)
+}
+
+/// Composite a color with a mask (SSSE3).
+///
+/// * `exact` Round products to nearest.
+///
+/// Returns the number of pixels blended (a multiple of 4).
 #[target_feature(enable = "ssse3")]
-unsafe fn over_x86(pix: &mut [Rgb<Ch8>], mask: &[u8], clr: Rgb<Ch8>) {
-    let len = pix.len().min(mask.len());
+unsafe fn over_ssse3(
+    pix: *mut u8,
+    mask: *const u8,
+    len: usize,
+    k: i32,
+    a: i32,
+    exact: bool,
+) -> usize {
+    let kv = _mm_set1_epi32(k);
+    let ca = _mm_set1_epi8(a as i8);
     // Truncate len to multiple of 4
-    let len = (len >> 2) << 2;
-    let clr = _mm_set1_epi32(clr.into());
-    let src = mask.as_ptr();
-    let dst = pix.as_mut_ptr();
+    let len4 = (len >> 2) << 2;
     // 4 pixels at a time
-    for i in (0..len).step_by(4) {
-        let off = i as isize;
-        let dst = dst.offset(off) as *mut __m128i;
-        let src = src.offset(off) as *const i32;
+    for i in (0..len4).step_by(4) {
+        let dst = pix.add(i * 4) as *mut __m128i;
+        let src = mask.add(i) as *const i32;
         // get 4 alpha values from src,
         // then shuffle: xxxxxxxxxxxx3210 => 3333222211110000
-        let alpha = swizzle_mask_x86(_mm_set1_epi32(*src));
+        let m = _mm_set1_epi32(std::ptr::read_unaligned(src));
+        let m = swizzle_mask_x86(m);
         // get RGBA values from dst
         let bot = _mm_loadu_si128(dst);
         // compose top over bot
-        let out = over_alpha_u8x16_x86(clr, bot, alpha);
+        let out = over_u8x16_x86(kv, ca, m, bot, exact);
         // store blended pixels
         _mm_storeu_si128(dst, out);
     }
+    len4
 }
 
 /// Swizzle alpha mask (xxxxxxxxxxxx3210 => 3333222211110000)
-#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"),
-          feature = "simd"))]
 #[target_feature(enable = "ssse3")]
 unsafe fn swizzle_mask_x86(v: __m128i) -> __m128i {
-    _mm_shuffle_epi8(v, _mm_set_epi8(3, 3, 3, 3,
-                                     2, 2, 2, 2,
-                                     1, 1, 1, 1,
-                                     0, 0, 0, 0))
+    _mm_shuffle_epi8(
+        v,
+        _mm_set_epi8(3, 3, 3, 3, 2, 2, 2, 2, 1, 1, 1, 1, 0, 0, 0, 0),
+    )
+}
+
+/// Composite packed mask color over packed pixels (SSSE3).
+///
+/// * `k` Mask color, separated from alpha (alpha lanes are ignored).  With
+///   `exact`, this is the associated color instead.
+/// * `ca` Mask color alpha.
+/// * `m` Mask values, for each channel.
+/// * `b` Bottom pixels.
+/// * `exact` Round products to nearest.
+#[target_feature(enable = "ssse3")]
+unsafe fn over_u8x16_x86(
+    k: __m128i,
+    ca: __m128i,
+    m: __m128i,
+    b: __m128i,
+    exact: bool,
+) -> __m128i {
+    let alpha = _mm_set1_epi32(0xFF00_0000_u32 as i32);
+    // Source alpha: mask alpha * mask value
+    let sa = mul_u8x16_x86(ca, m, exact);
+    // Source color: separated color * source alpha, or associated color *
+    // mask value when exact
+    let s = mul_u8x16_x86(k, if exact { m } else { sa }, exact);
+    let s = _mm_or_si128(_mm_andnot_si128(alpha, s), _mm_and_si128(alpha, sa));
+    // Over: s + b * (1 - sa)
+    let one_minus_sa = _mm_xor_si128(sa, _mm_set1_epi8(-1));
+    _mm_adds_epu8(s, mul_u8x16_x86(b, one_minus_sa, exact))
+}
+
+/// Multiply packed u8 values as `Ch8` channels (SSSE3).
+#[target_feature(enable = "ssse3")]
+unsafe fn mul_u8x16_x86(a: __m128i, b: __m128i, exact: bool) -> __m128i {
+    let zero = _mm_setzero_si128();
+    let al = _mm_unpacklo_epi8(a, zero);
+    let bl = _mm_unpacklo_epi8(b, zero);
+    let ah = _mm_unpackhi_epi8(a, zero);
+    let bh = _mm_unpackhi_epi8(b, zero);
+    let (lo, hi) = if exact {
+        (mul_exact_u16x8_x86(al, bl), mul_exact_u16x8_x86(ah, bh))
+    } else {
+        (mul_u16x8_x86(al, bl), mul_u16x8_x86(ah, bh))
+    };
+    _mm_packus_epi16(lo, hi)
 }
 
-/// Composite packed u8 values using `over`.
-#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"),
-          feature = "simd"))]
+/// Multiply u8 values in u16 lanes as `Ch8` channels (SSSE3).
 #[target_feature(enable = "ssse3")]
-unsafe fn over_alpha_u8x16_x86(t: __m128i, b: __m128i, a: __m128i) -> __m128i {
-    // Since alpha can range from 0 to 255 and (t - b) can range from -255 to
-    // +255, we would need 17 bits to store the result of a multiplication.
-    // Instead, shift alpha right by 1 bit (divide by 2).  Afterwards, we can
-    // shift back by one less bit (in scale_i16_to_u8_x86).
-    // For even lanes: b + alpha * (t - b)
-    let t_even = _mm_unpacklo_epi8(t, _mm_setzero_si128());
-    let b_even = _mm_unpacklo_epi8(b, _mm_setzero_si128());
-    let a_even = _mm_unpacklo_epi8(a, _mm_setzero_si128());
-    let a_even = _mm_srli_epi16(a_even, 1);
-    let even = _mm_mullo_epi16(a_even, _mm_sub_epi16(t_even, b_even));
-    let even = scale_i16_to_u8_x86(even);
-    let even = _mm_add_epi16(b_even, even);
-    // For odd lanes: b + alpha * (t - b)
-    let t_odd = _mm_unpackhi_epi8(t, _mm_setzero_si128());
-    let b_odd = _mm_unpackhi_epi8(b, _mm_setzero_si128());
-    let a_odd = _mm_unpackhi_epi8(a, _mm_setzero_si128());
-    let a_odd = _mm_srli_epi16(a_odd, 1);
-    let odd = _mm_mullo_epi16(a_odd, _mm_sub_epi16(t_odd, b_odd));
-    let odd = scale_i16_to_u8_x86(odd);
-    let odd = _mm_add_epi16(b_odd, odd);
-    _mm_packus_epi16(even, odd)
+unsafe fn mul_u16x8_x86(a: __m128i, b: __m128i) -> __m128i {
+    // Same as Ch8 multiply: expand each value to 12 bits (v << 4 | v >> 4),
+    // then keep the high 16 bits of the 24-bit product.
+    let a = _mm_or_si128(_mm_slli_epi16(a, 4), _mm_srli_epi16(a, 4));
+    let b = _mm_or_si128(_mm_slli_epi16(b, 4), _mm_srli_epi16(b, 4));
+    _mm_mulhi_epu16(a, b)
 }
 
-/// Scale i16 values (result of "u7" * "i9") into u8.
-#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"),
-          feature = "simd"))]
+/// Multiply u8 values in u16 lanes, rounding to nearest (SSSE3).
 #[target_feature(enable = "ssse3")]
-unsafe fn scale_i16_to_u8_x86(v: __m128i) -> __m128i {
-    // To scale into a u8, we would normally divide by 255.  This is equivalent
-    // to: ((v + 1) + (v >> 8)) >> 8
-    // For the last right shift, we use 7 instead to simulate multiplying by
-    // 2.  This is necessary because alpha was shifted right by 1 bit to allow
-    // fitting 17 bits of data into epi16 lanes.
-    _mm_srai_epi16(_mm_add_epi16(_mm_add_epi16(v,
-                                               _mm_set1_epi16(1)),
-                                 _mm_srai_epi16(v, 8)),
-                   7)
+unsafe fn mul_exact_u16x8_x86(a: __m128i, b: __m128i) -> __m128i {
+    // c = a * b + 128, then (c + (c >> 8)) >> 8
+    let c = _mm_add_epi16(_mm_mullo_epi16(a, b), _mm_set1_epi16(128));
+    _mm_srli_epi16(_mm_add_epi16(c, _mm_srli_epi16(c, 8)), 8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixture::{mask8, rgba8};
+    use crate::Blend;
+    use pix::*;
+
+    /// Check that pixels are within 1 of the fallback version.
+    fn assert_within_one(pix: &[AssocSRgba8], fallback: &[AssocSRgba8]) {
+        for (p, f) in pix.iter().zip(fallback) {
+            for (c, d) in p.rgba().iter().zip(f.rgba().iter()) {
+                let c = i32::from(u8::from(*c));
+                let d = i32::from(u8::from(*d));
+                assert!((c - d).abs() <= 1, "{:?} != {:?}", p, f);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_fallback() {
+        let colors = [
+            AssocSRgba8::new(0xFF, 0x80, 0x40),
+            AssocSRgba8::with_alpha(0x20, 0x40, 0x60, 0x80),
+            AssocSRgba8::with_alpha(0x01, 0x02, 0x03, 0x04),
+            AssocSRgba8::with_alpha(0x00, 0x00, 0x00, 0x00),
+        ];
+        let opacities = [0xFF, 0xB3, 0x2C].iter().cycle();
+        let rnd = Rounding::Fast;
+        for (len, o) in (0..300).zip(opacities) {
+            for clr in colors.iter() {
+                let (mut dst, mask) = (rgba8(len), mask8(len));
+                let mut fallback = dst.clone();
+                let o = Ch8::new(*o);
+                AssocSRgba8::over_slice(&mut dst, &mask, *clr, o, rnd);
+                AssocSRgba8::over_fallback(&mut fallback, &mask, *clr, o, rnd);
+                assert_within_one(&dst, &fallback);
+            }
+        }
+    }
+
+    #[test]
+    fn kernels() {
+        let clr = AssocSRgba8::with_alpha(0x40, 0x80, 0x60, 0xC0);
+        for o in [0xFF, 0xB3, 0x2C].iter().map(|o| Ch8::new(*o)) {
+            let (dst, mask) = (rgba8(256), mask8(256));
+            let mut fallback = dst.clone();
+            let rnd = Rounding::Fast;
+            AssocSRgba8::over_fallback(&mut fallback, &mask, clr, o, rnd);
+            let (k, a) = mask_color(clr, o, rnd);
+            let (ke, ae) = mask_color(clr, o, Rounding::Exact);
+            let mut exact = dst.clone();
+            over_exact(&mut exact, &mask, ke, ae);
+            let m = mask.as_ptr() as *const u8;
+            if is_x86_feature_detected!("ssse3") {
+                let mut pix = dst.clone();
+                let p = pix.as_mut_ptr() as *mut u8;
+                assert_eq!(unsafe { over_ssse3(p, m, 256, k, a, false) }, 256);
+                assert_eq!(pix, fallback);
+                let mut pix = dst.clone();
+                let p = pix.as_mut_ptr() as *mut u8;
+                let n = unsafe { over_ssse3(p, m, 256, ke, ae, true) };
+                assert_eq!(n, 256);
+                assert_eq!(pix, exact);
+            }
+            if is_x86_feature_detected!("avx2") {
+                let mut pix = dst.clone();
+                let p = pix.as_mut_ptr() as *mut u8;
+                assert_eq!(unsafe { over_avx2(p, m, 256, k, a, false) }, 256);
+                assert_eq!(pix, fallback);
+                let mut pix = dst;
+                let p = pix.as_mut_ptr() as *mut u8;
+                assert_eq!(unsafe { over_avx2(p, m, 256, ke, ae, true) }, 256);
+                assert_eq!(pix, exact);
+            }
+        }
+    }
+
+    /// Blend associated mask color over pixels the same way as the kernels,
+    /// with exact rounding.
+    fn over_exact(dst: &mut [AssocSRgba8], mask: &[Mask8], k: i32, a: i32) {
+        use crate::blend::mul_exact_u8 as mul;
+        let k = k.to_le_bytes();
+        for (d, m) in dst.iter_mut().zip(mask) {
+            let m = u8::from(m.alpha().value());
+            let sa = mul(a as u8, m);
+            let s = [mul(k[0], m), mul(k[1], m), mul(k[2], m), sa];
+            let rgba = d.rgba();
+            let mut out = [Ch8::new(0); 4];
+            for i in 0..4 {
+                let b = mul(u8::from(rgba[i]), 0xFF - sa);
+                out[i] = Ch8::new(s[i].saturating_add(b));
+            }
+            *d = AssocSRgba8::with_rgba(out);
+        }
+    }
 }
@@ -0,0 +1,141 @@
+// shadow.rs    Drop shadows and glows.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use crate::raster::Parallel;
+use crate::{blur_gaussian, raster_over, Blend, Rect, Rounding};
+use pix::{Ch32, Channel, Format, Raster, RasterBuilder};
+
+/// Blend a drop shadow of a mask with `over` operation.
+///
+/// The mask is blurred, offset and tinted with a color.  Draw the shadow
+/// before drawing the mask itself.
+///
+/// * `dst` Destination target.
+/// * `mask` Mask target, positioned at the destination origin.
+/// * `clr` Shadow color.
+/// * `opacity` Opacity of shadow (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `offset` Offset of shadow (*x*, *y*).
+/// * `radius` Blur radius, in pixels (Gaussian sigma is half the radius,
+///   as with CSS `box-shadow`).  It is limited to the larger dimension of
+///   the destination, and a NaN radius is treated as zero.
+///
+/// Returns the affected destination rectangle, or `None` if the shadow is
+/// positioned off an edge.
+pub fn drop_shadow<A, B, C, H>(
+    dst: &mut Raster<A>,
+    mask: &Raster<B>,
+    clr: C,
+    opacity: H,
+    rnd: Rounding,
+    offset: (i32, i32),
+    radius: f32,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + From<Ch32> + Parallel,
+    Ch32: From<H>,
+{
+    let max = dst.width().max(dst.height()) as f32;
+    let sigma = radius.max(0.0).min(max) / 2.0;
+    let pad = (sigma * 3.0).ceil() as u32;
+    let mut shadow = padded(mask, pad);
+    blur_gaussian(&mut shadow, sigma);
+    let pad = pad as i32;
+    let (dx, dy) = offset;
+    let (x, y) = (dx.saturating_sub(pad), dy.saturating_sub(pad));
+    raster_over(dst, &shadow, clr, opacity, rnd, x, y)
+}
+
+/// Blend an outer glow of a mask with `over` operation.
+///
+/// This is a drop shadow with no offset.  Draw the glow before drawing the
+/// mask itself.
+///
+/// * `dst` Destination target.
+/// * `mask` Mask target, positioned at the destination origin.
+/// * `clr` Glow color.
+/// * `opacity` Opacity of glow (`MAX` is opaque).
+/// * `rnd` Rounding policy.
+/// * `radius` Blur radius, in pixels.
+///
+/// Returns the affected destination rectangle, or `None` if the glow is
+/// positioned off an edge.
+pub fn outer_glow<A, B, C, H>(
+    dst: &mut Raster<A>,
+    mask: &Raster<B>,
+    clr: C,
+    opacity: H,
+    rnd: Rounding,
+    radius: f32,
+) -> Option<Rect>
+where
+    A: Blend + From<C> + Parallel,
+    B: Format<Chan = H> + Parallel,
+    A::Chan: From<H>,
+    H: Channel + From<A::Chan> + From<Ch32> + Parallel,
+    Ch32: From<H>,
+{
+    drop_shadow(dst, mask, clr, opacity, rnd, (0, 0), radius)
+}
+
+/// Copy a raster, with transparent padding on each side.
+fn padded<B: Format>(src: &Raster<B>, pad: u32) -> Raster<B> {
+    let w = src.width() + pad * 2;
+    let h = src.height() + pad * 2;
+    let mut r = RasterBuilder::new().with_clear(w, h);
+    let (sw, w, pad) = (src.width() as usize, w as usize, pad as usize);
+    if sw > 0 {
+        let rows = r.as_slice_mut().chunks_mut(w).skip(pad);
+        for (row, s) in rows.zip(src.as_slice().chunks(sw)) {
+            row[pad..pad + sw].copy_from_slice(s);
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pix::*;
+
+    #[test]
+    fn shadow() {
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(2, 2, Mask8::new(0xFF));
+        let c = SepSRgba8::new(0x00, 0x00, 0x00);
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(8, 8);
+        let (o, rnd) = (Ch8::MAX, Rounding::Fast);
+        let rect = drop_shadow(&mut r, &m, c, o, rnd, (3, 3), 2.0);
+        assert_eq!(rect, Some(Rect::new(0, 0, 8, 8)));
+        let a = |x, y| r.pixel(x, y).alpha().value();
+        assert!(a(3, 3) > a(2, 2));
+        assert!(a(2, 2) > a(1, 1));
+        assert_eq!(a(0, 0), Ch8::new(0));
+        assert_eq!(a(3, 4), a(4, 3));
+        let mut g = RasterBuilder::<SepSRgba8>::new().with_clear(8, 8);
+        let rect = outer_glow(&mut g, &m, c, o, rnd, 0.0);
+        assert_eq!(rect, Some(Rect::new(0, 0, 2, 2)));
+        assert_eq!(g.pixel(1, 1), SepSRgba8::new(0x00, 0x00, 0x00));
+        let mut h = RasterBuilder::<SepSRgba8>::new().with_clear(8, 8);
+        outer_glow(&mut h, &m, c, Ch8::new(0x80), rnd, 0.0);
+        assert_eq!(h.pixel(1, 1).alpha().value(), Ch8::new(0x80));
+    }
+
+    #[test]
+    fn large_radius() {
+        let m =
+            RasterBuilder::<Mask8>::new().with_color(2, 2, Mask8::new(0xFF));
+        let c = SepSRgba8::new(0x00, 0x00, 0x00);
+        let (o, rnd) = (Ch8::MAX, Rounding::Fast);
+        let mut r = RasterBuilder::<SepSRgba8>::new().with_clear(4, 4);
+        let rect = drop_shadow(&mut r, &m, c, o, rnd, (1, 1), f32::INFINITY);
+        assert_eq!(rect, Some(Rect::new(0, 0, 4, 4)));
+        let mut g = RasterBuilder::<SepSRgba8>::new().with_clear(4, 4);
+        let rect = outer_glow(&mut g, &m, c, o, rnd, f32::NAN);
+        assert_eq!(rect, Some(Rect::new(0, 0, 2, 2)));
+    }
+}
@@ -0,0 +1,175 @@
+// transform.rs Affine transforms.
+//
+// Copyright (c) 2020  Douglas P Lau
+//
+use std::ops::Mul;
+
+/// An affine transform for 2D points.
+///
+/// Transforms can be chained, with operations applied in order:
+///
+/// ```
+/// # use pixops::Transform;
+/// let t = Transform::default().scale(2.0, 2.0).translate(10.0, 0.0);
+/// assert_eq!(t.transform(1.0, 1.0), (12.0, 2.0));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    /// First two rows of a 3x3 matrix
+    e: [f32; 6],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            e: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        }
+    }
+}
+
+impl From<[f32; 6]> for Transform {
+    /// Create a transform from the first two rows of a 3x3 matrix
+    fn from(e: [f32; 6]) -> Self {
+        Transform { e }
+    }
+}
+
+impl Mul for Transform {
+    type Output = Self;
+
+    /// Multiply two transforms (`rhs` is applied first)
+    fn mul(self, rhs: Self) -> Self {
+        let a = &self.e;
+        let b = &rhs.e;
+        Transform {
+            e: [
+                a[0] * b[0] + a[1] * b[3],
+                a[0] * b[1] + a[1] * b[4],
+                a[0] * b[2] + a[1] * b[5] + a[2],
+                a[3] * b[0] + a[4] * b[3],
+                a[3] * b[1] + a[4] * b[4],
+                a[3] * b[2] + a[4] * b[5] + a[5],
+            ],
+        }
+    }
+}
+
+impl Transform {
+    /// Create a new transform from matrix elements
+    ///
+    /// Points are transformed to `(a * x + b * y + c, d * x + e * y + f)`.
+    pub fn new(a: f32, b: f32, c: f32, d: f32, e: f32, f: f32) -> Self {
+        Transform {
+            e: [a, b, c, d, e, f],
+        }
+    }
+
+    /// Create a new translation transform
+    pub fn new_translate(tx: f32, ty: f32) -> Self {
+        Transform {
+            e: [1.0, 0.0, tx, 0.0, 1.0, ty],
+        }
+    }
+
+    /// Create a new scale transform
+    pub fn new_scale(sx: f32, sy: f32) -> Self {
+        Transform {
+            e: [sx, 0.0, 0.0, 0.0, sy, 0.0],
+        }
+    }
+
+    /// Create a new rotation transform
+    ///
+    /// * `th` Angle to rotate (radians).
+    pub fn new_rotate(th: f32) -> Self {
+        let (s, c) = th.sin_cos();
+        Transform {
+            e: [c, -s, 0.0, s, c, 0.0],
+        }
+    }
+
+    /// Create a new skew transform
+    ///
+    /// * `ax` Angle to skew on X axis (radians).
+    /// * `ay` Angle to skew on Y axis (radians).
+    pub fn new_skew(ax: f32, ay: f32) -> Self {
+        Transform {
+            e: [1.0, ax.tan(), 0.0, ay.tan(), 1.0, 0.0],
+        }
+    }
+
+    /// Apply a translation after the transform
+    pub fn translate(self, tx: f32, ty: f32) -> Self {
+        Transform::new_translate(tx, ty) * self
+    }
+
+    /// Apply a scale after the transform
+    pub fn scale(self, sx: f32, sy: f32) -> Self {
+        Transform::new_scale(sx, sy) * self
+    }
+
+    /// Apply a rotation after the transform
+    pub fn rotate(self, th: f32) -> Self {
+        Transform::new_rotate(th) * self
+    }
+
+    /// Apply a skew after the transform
+    pub fn skew(self, ax: f32, ay: f32) -> Self {
+        Transform::new_skew(ax, ay) * self
+    }
+
+    /// Get the inverse transform, or `None` if it is not invertible
+    pub fn inverse(self) -> Option<Self> {
+        let e = &self.e;
+        let det = e[0] * e[4] - e[1] * e[3];
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+        let a = e[4] / det;
+        let b = -e[1] / det;
+        let d = -e[3] / det;
+        let f = e[0] / det;
+        Some(Transform {
+            e: [
+                a,
+                b,
+                -(a * e[2] + b * e[5]),
+                d,
+                f,
+                -(d * e[2] + f * e[5]),
+            ],
+        })
+    }
+
+    /// Transform a point
+    pub fn transform(self, x: f32, y: f32) -> (f32, f32) {
+        let e = &self.e;
+        (e[0] * x + e[1] * y + e[2], e[3] * x + e[4] * y + e[5])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inverse() {
+        let t = Transform::new_rotate(0.5)
+            .skew(0.25, 0.0)
+            .scale(2.0, 3.0)
+            .translate(-5.0, 7.0);
+        let (x, y) = t.transform(3.0, 4.0);
+        let (x, y) = t.inverse().unwrap().transform(x, y);
+        assert!((x - 3.0).abs() < 1e-4 && (y - 4.0).abs() < 1e-4);
+        assert_eq!(Transform::new_scale(0.0, 1.0).inverse(), None);
+    }
+
+    #[test]
+    fn matrix() {
+        let t = Transform::new(2.0, 0.5, 10.0, -1.0, 3.0, 0.0);
+        assert_eq!(t.transform(2.0, 4.0), (16.0, 10.0));
+        assert_eq!(t, Transform::from([2.0, 0.5, 10.0, -1.0, 3.0, 0.0]));
+        let t = Transform::new_scale(2.0, 3.0).translate(1.0, -1.0);
+        assert_eq!(t, Transform::new(2.0, 0.0, 1.0, 0.0, 3.0, -1.0));
+    }
+}